@@ -1,6 +0,0 @@
-const process = {
-  env: {
-    NODE_ENV: "production",
-  },
-};
-globalThis.process = process;
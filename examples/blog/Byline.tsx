@@ -0,0 +1,11 @@
+import styles from "./Byline.module.scss";
+
+const Byline = () => {
+  return (
+    <p className={styles.byline}>
+      Posted in the <em>blog</em>
+    </p>
+  );
+};
+
+export default Byline;
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use esbuild_rs::{
+    BuildOptionsBuilder, BuildResult, Format, Loader, Message, Platform, SourceMap, Target,
+};
+
+use crate::diagnostic::Diagnostic;
+use crate::mode::Mode;
+use crate::project::Project;
+use crate::resolve::TSCONFIG_FILE;
+
+/// Which side of the page a bundle is built for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleTarget {
+    /// Code split browser bundle that hydrates the page, minified in production.
+    Client,
+    /// Unminified bundle evaluated in the SSR `JsRuntime`.
+    Server,
+    /// Stylesheet combining the CSS files an entry `@import`s, minified in production.
+    Stylesheet,
+}
+
+/// Settings shared by every bundle of a build.
+#[derive(Debug, Clone)]
+pub struct BundleOptions {
+    pub mode: Mode,
+    /// Applied to every file, so components outside the client directory are compiled
+    /// like the ones inside it.
+    pub tsconfig: PathBuf,
+    /// Where packages imported by files outside the client directory are found.
+    pub node_paths: Vec<PathBuf>,
+}
+
+impl BundleOptions {
+    pub fn new(project: &Project, mode: Mode) -> Self {
+        Self {
+            mode,
+            tsconfig: project.path(TSCONFIG_FILE),
+            node_paths: vec![project.path("client/node_modules")],
+        }
+    }
+}
+
+/// Bundles every file in `entries` in a single esbuild run. Each bundle is written to
+/// `outdir` at its entry's path relative to [`common_dir`], with a `.js` extension. Client bundles are code split, so modules
+/// shared by several entries go into `chunk-<hash>.js` files at the root of `outdir`.
+/// Server bundles get every file in `inject` prepended, which is how polyfills are provided.
+///
+/// On success the warnings esbuild reported are returned, on failure its errors. A failure
+/// in any entry fails the whole run.
+pub async fn bundle(
+    target: BundleTarget,
+    options: &BundleOptions,
+    entries: &[PathBuf],
+    outdir: &Path,
+    inject: &[PathBuf],
+) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+    let mut builder = BuildOptionsBuilder::new();
+    builder.entry_points.extend(
+        entries
+            .iter()
+            .map(|entry| entry.to_string_lossy().into_owned()),
+    );
+    builder.outdir = outdir.to_string_lossy().into_owned();
+    builder.bundle = true;
+    builder.write = true;
+    builder.format = Format::ESModule;
+    builder.loader = loaders();
+    builder.define = defines(target, options.mode);
+    if options.tsconfig.is_file() {
+        builder.tsconfig = options.tsconfig.to_string_lossy().into_owned();
+    }
+    builder.node_paths.extend(
+        options
+            .node_paths
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned()),
+    );
+
+    match target {
+        BundleTarget::Client => {
+            builder.target = Target::ES2015;
+            builder.platform = Platform::Browser;
+            builder.splitting = true;
+            if options.mode.is_dev() {
+                builder.source_map = SourceMap::Linked;
+            } else {
+                // Unlinked sourcemaps are only read to attribute bundle sizes to modules
+                builder.source_map = SourceMap::External;
+                builder.minify_whitespace = true;
+                builder.minify_identifiers = true;
+                builder.minify_syntax = true;
+            }
+        }
+        BundleTarget::Stylesheet => {
+            builder.platform = Platform::Browser;
+            if options.mode.is_dev() {
+                builder.source_map = SourceMap::Linked;
+            } else {
+                builder.source_map = SourceMap::External;
+                builder.minify_whitespace = true;
+                builder.minify_syntax = true;
+            }
+        }
+        BundleTarget::Server => {
+            // Resolving the browser build of `react-dom/server` keeps Node builtins such as
+            // `stream` and `util` out of the bundle, so they don't need polyfilling for V8
+            builder.target = Target::ES2020;
+            builder.platform = Platform::Browser;
+            builder.inject.extend(
+                inject
+                    .iter()
+                    .map(|path| path.to_string_lossy().into_owned()),
+            );
+        }
+    }
+
+    let result = esbuild_rs::build(builder.build()).await;
+    collect_diagnostics(&result)
+}
+
+/// The deepest directory containing every entry. esbuild mirrors the entries' layout below
+/// it into `outdir`, so passing it as `outdir` puts each bundle beside its entry.
+pub fn common_dir(entries: &[PathBuf]) -> PathBuf {
+    let mut dirs = entries.iter().filter_map(|entry| entry.parent());
+    let Some(first) = dirs.next() else {
+        return PathBuf::new();
+    };
+    dirs.fold(first.to_path_buf(), |common, dir| {
+        common
+            .ancestors()
+            .find(|ancestor| dir.starts_with(ancestor))
+            .unwrap_or(Path::new(""))
+            .to_path_buf()
+    })
+}
+
+fn loaders() -> HashMap<String, Loader> {
+    [
+        (".ts", Loader::TS),
+        (".tsx", Loader::TSX),
+        (".js", Loader::JS),
+        (".jsx", Loader::JSX),
+        (".json", Loader::JSON),
+        (".css", Loader::CSS),
+    ]
+    .into_iter()
+    .map(|(ext, loader)| (ext.to_string(), loader))
+    .collect()
+}
+
+fn defines(target: BundleTarget, mode: Mode) -> HashMap<String, String> {
+    let mut defines = HashMap::from([(
+        "process.env.NODE_ENV".to_string(),
+        format!("{:?}", mode.node_env()),
+    )]);
+
+    // The SSR runtime is a bare V8 isolate, so browser globals alias `globalThis`
+    if target == BundleTarget::Server {
+        for global in ["global", "window", "self"] {
+            defines.insert(global.to_string(), "globalThis".to_string());
+        }
+        defines.insert("URL".to_string(), "globalThis.URL".to_string());
+    }
+
+    defines
+}
+
+fn collect_diagnostics(result: &BuildResult) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+    let warnings = result
+        .warnings
+        .as_slice()
+        .iter()
+        .map(|message| Diagnostic::warning(format_message(message)))
+        .collect();
+
+    let errors: Vec<_> = result
+        .errors
+        .as_slice()
+        .iter()
+        .map(|message| Diagnostic::error(format_message(message)))
+        .collect();
+
+    if errors.is_empty() {
+        Ok(warnings)
+    } else {
+        Err(errors)
+    }
+}
+
+/// esbuild leaves `file` empty for messages that aren't about a location in a file.
+fn format_message(message: &Message) -> String {
+    let file = message.file.as_str();
+    if file.is_empty() {
+        message.text.as_str().to_string()
+    } else {
+        format!(
+            "{}:{}:{}: {}",
+            file,
+            message.line,
+            message.column + 1,
+            message.text.as_str()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aliases_browser_globals_on_the_server() {
+        let defines = defines(BundleTarget::Server, Mode::Dev);
+        for global in ["global", "window", "self"] {
+            assert_eq!(defines[global], "globalThis");
+        }
+        assert_eq!(defines["URL"], "globalThis.URL");
+        assert_eq!(defines["process.env.NODE_ENV"], "\"development\"");
+    }
+
+    #[test]
+    fn leaves_browser_globals_alone_on_the_client() {
+        let defines = defines(BundleTarget::Client, Mode::Dev);
+        assert_eq!(defines.len(), 1);
+    }
+
+    #[test]
+    fn bundles_css_as_plain_css() {
+        // esbuild-rs can't run plugins, so CSS modules are scoped by `sass` before
+        // bundling rather than by a loader
+        assert!(matches!(loaders()[".css"], Loader::CSS));
+    }
+}
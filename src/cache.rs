@@ -0,0 +1,324 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+
+use crate::config::CONFIG_FILE;
+use crate::error::{Error, Result};
+use crate::project::Project;
+use crate::public::PublicFiles;
+use crate::resolve::{ImportResolver, TSCONFIG_FILE};
+use crate::template::{PageSummary, PARTIALS_DIR};
+
+/// File in the project's cache directory that records what every page was built from.
+pub const CACHE_FILE: &str = "build-cache.json";
+
+/// Hashes of everything that decides a page's output apart from the components it
+/// imports, which are tracked per file in [`PageRecord::dependencies`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PageInputs {
+    /// The page's own Markdown.
+    pub source: String,
+    /// Build options that change how Markdown is turned into JSX.
+    pub options: String,
+    /// Config, client packages and aliases, shared stylesheets, fingerprinted public
+    /// files, partials and the summaries of every page, which all pages can render.
+    pub site: String,
+    /// The entry and HTML templates the page resolves to.
+    pub templates: String,
+}
+
+/// What a page was last built from and what it produced.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageRecord {
+    pub inputs: PageInputs,
+    /// Hash of every local file reachable from the page's imports.
+    pub dependencies: BTreeMap<PathBuf, String>,
+    pub outputs: Vec<PathBuf>,
+    pub summary: PageSummary,
+}
+
+/// Why a page is being rebuilt rather than reused from the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RebuildReason {
+    Forced,
+    NotCached,
+    SourceChanged,
+    OptionsChanged,
+    SiteChanged,
+    TemplateChanged,
+    DependencyChanged(PathBuf),
+    OutputMissing(PathBuf),
+}
+
+impl fmt::Display for RebuildReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RebuildReason::Forced => write!(f, "--force was passed"),
+            RebuildReason::NotCached => write!(f, "not built before"),
+            RebuildReason::SourceChanged => write!(f, "source changed"),
+            RebuildReason::OptionsChanged => write!(f, "build options changed"),
+            RebuildReason::SiteChanged => {
+                write!(
+                    f,
+                    "config, packages, tsconfig, shared styles, public files, partials or another page's summary changed"
+                )
+            }
+            RebuildReason::TemplateChanged => write!(f, "template changed"),
+            RebuildReason::DependencyChanged(path) => write!(f, "{} changed", path.display()),
+            RebuildReason::OutputMissing(path) => write!(f, "{} is missing", path.display()),
+        }
+    }
+}
+
+impl PageRecord {
+    /// Returns why the page has to be rebuilt given its current inputs, or `None` when
+    /// its outputs are still up to date.
+    pub fn stale(&self, inputs: &PageInputs) -> Option<RebuildReason> {
+        if self.inputs.source != inputs.source {
+            return Some(RebuildReason::SourceChanged);
+        }
+        if self.inputs.options != inputs.options {
+            return Some(RebuildReason::OptionsChanged);
+        }
+        if self.inputs.site != inputs.site {
+            return Some(RebuildReason::SiteChanged);
+        }
+        if self.inputs.templates != inputs.templates {
+            return Some(RebuildReason::TemplateChanged);
+        }
+        if let Some((path, _)) = self
+            .dependencies
+            .iter()
+            .find(|(path, hash)| hash_file(path).as_ref() != Some(*hash))
+        {
+            return Some(RebuildReason::DependencyChanged(path.clone()));
+        }
+        self.outputs
+            .iter()
+            .find(|path| !path.is_file())
+            .map(|path| RebuildReason::OutputMissing(path.clone()))
+    }
+}
+
+/// Records from the previous build, keyed by page path.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BuildCache {
+    version: String,
+    pages: BTreeMap<PathBuf, PageRecord>,
+}
+
+impl BuildCache {
+    /// Loads the cache from the last build. A missing or unreadable cache, or one written by
+    /// another version of Kaffe, is treated as empty so every page gets rebuilt.
+    pub fn load(project: &Project) -> Self {
+        fs::read_to_string(project.cache_dir().join(CACHE_FILE))
+            .ok()
+            .and_then(|contents| serde_json::from_str::<BuildCache>(&contents).ok())
+            .filter(|cache| cache.version == env!("CARGO_PKG_VERSION"))
+            .unwrap_or_default()
+    }
+
+    pub fn save(&mut self, project: &Project) -> Result<()> {
+        self.version = env!("CARGO_PKG_VERSION").to_string();
+        let path = project.cache_dir().join(CACHE_FILE);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+        }
+        let contents =
+            serde_json::to_string_pretty(self).map_err(|e| Error::io(&path, e.into()))?;
+        fs::write(&path, contents).map_err(|e| Error::io(&path, e))
+    }
+
+    pub fn get(&self, page: &Path) -> Option<&PageRecord> {
+        self.pages.get(page)
+    }
+
+    pub fn insert(&mut self, page: PathBuf, record: PageRecord) {
+        self.pages.insert(page, record);
+    }
+
+    pub fn remove(&mut self, page: &Path) {
+        self.pages.remove(page);
+    }
+
+    /// Drops the records of pages that no longer exist.
+    pub fn retain(&mut self, pages: &[PathBuf]) {
+        self.pages.retain(|page, _| pages.contains(page));
+    }
+}
+
+pub fn hash_bytes(bytes: impl AsRef<[u8]>) -> String {
+    hex(&Sha256::digest(bytes))
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn hash_file(path: &Path) -> Option<String> {
+    fs::read(path).ok().map(hash_bytes)
+}
+
+/// Hashes the inputs every page shares: the config, the client's packages and import
+/// aliases, the stylesheets pages share, the names of fingerprinted public files, the
+/// template partials and the summaries of all pages.
+pub fn site_hash(
+    project: &Project,
+    pages: &[PageSummary],
+    shared_styles: &[PathBuf],
+    public: &PublicFiles,
+) -> String {
+    let mut hasher = Sha256::new();
+    for path in [
+        project.path(CONFIG_FILE),
+        project.path("client/package.json"),
+        project.path(TSCONFIG_FILE),
+    ] {
+        hasher.update(fs::read(path).unwrap_or_default());
+    }
+    for path in shared_styles {
+        hasher.update(path.to_string_lossy().as_bytes());
+        hasher.update(fs::read(path).unwrap_or_default());
+    }
+    for (url, copied) in public.fingerprinted() {
+        hasher.update(format!("{}={}\n", url, copied));
+    }
+    for entry in WalkDir::new(project.path(PARTIALS_DIR))
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+    {
+        hasher.update(entry.path().to_string_lossy().as_bytes());
+        hasher.update(fs::read(entry.path()).unwrap_or_default());
+    }
+    hasher.update(serde_json::to_vec(pages).unwrap_or_default());
+    hex(&hasher.finalize())
+}
+
+/// Finds and hashes every local file reachable from the generated `entries`, which are
+/// scanned but not recorded themselves. Bare package imports are not followed.
+pub fn dependencies(entries: &[PathBuf], resolver: &ImportResolver) -> BTreeMap<PathBuf, String> {
+    let mut pending: Vec<PathBuf> = entries
+        .iter()
+        .flat_map(|entry| {
+            resolver.imports_of(entry, &fs::read_to_string(entry).unwrap_or_default())
+        })
+        .collect();
+    let mut seen = BTreeSet::new();
+    let mut hashes = BTreeMap::new();
+
+    while let Some(path) = pending.pop() {
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        let Ok(contents) = fs::read(&path) else {
+            continue;
+        };
+        if let Ok(text) = std::str::from_utf8(&contents) {
+            pending.extend(resolver.imports_of(&path, text));
+        }
+        hashes.insert(path, hash_bytes(&contents));
+    }
+
+    hashes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inputs() -> PageInputs {
+        PageInputs {
+            source: hash_bytes("# Home"),
+            options: "options".to_string(),
+            site: "site".to_string(),
+            templates: "templates".to_string(),
+        }
+    }
+
+    fn record(dir: &Path) -> PageRecord {
+        let component = dir.join("Counter.tsx");
+        let output = dir.join("index.html");
+        fs::write(&component, "export default () => null;").unwrap();
+        fs::write(&output, "<p>Home</p>").unwrap();
+        PageRecord {
+            inputs: inputs(),
+            dependencies: BTreeMap::from([(component.clone(), hash_file(&component).unwrap())]),
+            outputs: vec![output],
+            summary: PageSummary {
+                url: "/index.html".to_string(),
+                title: "Home".to_string(),
+                frontmatter: Default::default(),
+                headings: Vec::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn reuses_pages_whose_inputs_and_outputs_are_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(record(dir.path()).stale(&inputs()), None);
+    }
+
+    #[test]
+    fn reports_the_first_changed_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(dir.path());
+        let changed = PageInputs {
+            options: "other".to_string(),
+            templates: "other".to_string(),
+            ..inputs()
+        };
+        assert_eq!(record.stale(&changed), Some(RebuildReason::OptionsChanged));
+        let changed = PageInputs {
+            source: hash_bytes("# Away"),
+            ..changed
+        };
+        assert_eq!(record.stale(&changed), Some(RebuildReason::SourceChanged));
+        let changed = PageInputs {
+            site: "other".to_string(),
+            ..inputs()
+        };
+        assert_eq!(record.stale(&changed), Some(RebuildReason::SiteChanged));
+        let changed = PageInputs {
+            templates: "other".to_string(),
+            ..inputs()
+        };
+        assert_eq!(record.stale(&changed), Some(RebuildReason::TemplateChanged));
+    }
+
+    #[test]
+    fn rebuilds_pages_whose_dependencies_changed_or_went_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(dir.path());
+        let component = dir.path().join("Counter.tsx");
+        fs::write(&component, "export default () => 1;").unwrap();
+        assert_eq!(
+            record.stale(&inputs()),
+            Some(RebuildReason::DependencyChanged(component.clone()))
+        );
+        fs::remove_file(&component).unwrap();
+        assert_eq!(
+            record.stale(&inputs()),
+            Some(RebuildReason::DependencyChanged(component))
+        );
+    }
+
+    #[test]
+    fn rebuilds_pages_whose_outputs_are_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let record = record(dir.path());
+        let output = dir.path().join("index.html");
+        fs::remove_file(&output).unwrap();
+        assert_eq!(
+            record.stale(&inputs()),
+            Some(RebuildReason::OutputMissing(output))
+        );
+    }
+}
@@ -0,0 +1,533 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::diagnostic::Diagnostic;
+use crate::images::ProcessedImage;
+use crate::loader::{LOADER_EXPORT, LOADER_GLOBAL, STATIC_PROPS_GLOBAL};
+use crate::mapping::{ComponentMap, ComponentMapping};
+use crate::parser::{ASTNode, Frontmatter, Hydration, ImportType, Prop, Rendering};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Heading {
+    pub level: u8,
+    pub text: String,
+    pub id: String,
+}
+
+/// Element the server wraps around each component instance, which the client hydrates
+/// on its own.
+pub const ISLAND_ELEMENT: &str = "kaffe-island";
+
+/// A component instance on a page, hydrated separately from the static Markdown around
+/// it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Island {
+    /// Numbers the page's islands in order, matching the `data-island` attribute of its
+    /// [`ISLAND_ELEMENT`].
+    pub id: usize,
+    pub component: String,
+    pub props: Vec<Prop>,
+    /// When the island hydrates. Never [`Hydration::None`], which isn't an island.
+    pub hydration: Hydration,
+}
+
+/// Global the server bundle serializes island props through, defined by
+/// [`PROPS_SERIALIZER`].
+pub const PROPS_GLOBAL: &str = "__kaffeIslands";
+
+/// Script run in the SSR runtime before the server bundle. `serialize` turns an island's
+/// props, or the page's static props, into JSON for a `<script type="application/json">`,
+/// and records every prop that JSON can't carry to the browser intact in `problems`, as
+/// `[message, help]`. `owner` says where the props came from in those messages.
+pub const PROPS_SERIALIZER: &str = r#"globalThis.__kaffeIslands = {
+  problems: [],
+  serialize(props, owner) {
+    const report = (path, what, help) => this.problems.push([
+      `Prop \`${path}\` ${owner} ${what}, which can't be serialized for hydration`,
+      help,
+    ]);
+    const check = (value, path) => {
+      if (typeof value === "function") {
+        report(path, "is a function", "define it inside the component, or render the component with client:none");
+      } else if (value instanceof Date) {
+        report(path, "is a Date", "pass `date.toISOString()` and create the Date inside the component");
+      } else if (typeof value === "bigint" || typeof value === "symbol") {
+        report(path, `is a ${typeof value}`, "pass a string or a number instead");
+      } else if (typeof value === "number" && !Number.isFinite(value)) {
+        report(path, `is ${value}`, "pass a finite number or null instead");
+      } else if (Array.isArray(value)) {
+        value.forEach((item, i) => item === undefined
+          ? report(`${path}[${i}]`, "is undefined", "pass null instead, which is what JSON turns it into")
+          : check(item, `${path}[${i}]`));
+      } else if (value !== null && typeof value === "object") {
+        const prototype = Object.getPrototypeOf(value);
+        if (value.$$typeof) {
+          report(path, "is a React element", "render it inside the component instead");
+        } else if (prototype !== Object.prototype && prototype !== null) {
+          const name = (value.constructor && value.constructor.name) || "class instance";
+          report(path, `is a ${name}`, "pass a plain object or an array instead");
+        } else {
+          Object.entries(value).forEach(([key, item]) => check(item, `${path}.${key}`));
+        }
+      }
+    };
+    Object.entries(props).forEach(([name, value]) => check(value, name));
+    // Keeps the JSON from closing the script element it's written into
+    return JSON.stringify(props).replace(/</g, "\\u003c");
+  },
+};"#;
+
+impl Island {
+    /// The props written on the component as a JavaScript object literal.
+    fn props_js(&self) -> String {
+        let props: Vec<_> = self.props.iter().map(Prop::to_js).collect();
+        if props.is_empty() {
+            "{}".to_string()
+        } else {
+            format!("{{ {} }}", props.join(", "))
+        }
+    }
+
+    /// The island as server JSX. Its props are evaluated once, both rendered with and
+    /// written as JSON to a script just before the island, where the client reads them
+    /// back. `display: contents` keeps the wrapper out of the page's layout. A
+    /// `client:only` island holds just its fallback text until the browser renders it.
+    /// `static_props` spreads the page's static props into the component, under its own.
+    pub fn to_jsx(&self, static_props: &str) -> String {
+        let content = match &self.hydration {
+            Hydration::Only(fallback) => fallback
+                .as_ref()
+                .map(|text| format!("{{{}}}", serde_json::to_string(text).unwrap_or_default()))
+                .unwrap_or_default(),
+            _ => format!("<{}{} {{...props}} />", self.component, static_props),
+        };
+        format!(
+            "{{(props => <><script type=\"application/json\" data-island-props=\"{id}\" \
+             dangerouslySetInnerHTML={{{{ __html: (globalThis as any).{global}.serialize(props, \
+             \"of <{component}> (island {id})\") }}}} /><{element} data-island=\"{id}\" \
+             style={{{{ display: \"contents\" }}}}>{content}</{element}></>)({props})}}",
+            id = self.id,
+            global = PROPS_GLOBAL,
+            component = self.component,
+            element = ISLAND_ELEMENT,
+            content = content,
+            props = self.props_js(),
+        )
+    }
+
+    /// The island as an entry of the client entry's `islands` array.
+    pub fn to_js(&self) -> String {
+        let strategy = match &self.hydration {
+            Hydration::Load | Hydration::None => "\"load\"".to_string(),
+            Hydration::Idle => "\"idle\"".to_string(),
+            Hydration::Visible => "\"visible\"".to_string(),
+            Hydration::Only(_) => "\"only\"".to_string(),
+            Hydration::Media(query) => format!(
+                "\"media\", {}",
+                serde_json::to_string(query).unwrap_or_default()
+            ),
+        };
+        format!("[\"{}\", {}, {}]", self.id, self.component, strategy)
+    }
+}
+
+/// Everything produced from a single Markdown page, ready to be spliced into the
+/// server and client entry templates.
+#[derive(Debug, Clone, Default)]
+pub struct GeneratedPage {
+    /// JSX markup for the page body.
+    pub markup: String,
+    pub imports: Vec<ImportType>,
+    /// Components referenced by the page, including mapped elements.
+    pub components: Vec<String>,
+    /// Component instances the client hydrates, in page order. Mapped elements render
+    /// on the server only.
+    pub islands: Vec<Island>,
+    pub headings: Vec<Heading>,
+    pub frontmatter: Frontmatter,
+    /// The module the page's `getStaticProps` is imported from, as written.
+    pub loader: Option<String>,
+    /// Local images shown on the page.
+    pub images: Vec<ProcessedImage>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl GeneratedPage {
+    /// The import statements the server entry needs, one per line: all of them but the
+    /// client-only components', followed by exposing the page's loader to Kaffe.
+    pub fn imports_js(&self) -> String {
+        let mut statements: Vec<_> = self
+            .imports
+            .iter()
+            .filter(|import| import.rendering() != Rendering::Client)
+            .map(ImportType::to_statement)
+            .collect();
+        if self.loader.is_some() {
+            statements.push(format!(
+                "(globalThis as any).{} = {};",
+                LOADER_GLOBAL, LOADER_EXPORT
+            ));
+        }
+        statements.join("\n")
+    }
+
+    /// The import statements the client entry needs: the ones binding a component
+    /// hydrated as an island, and the ones imported for their side effects.
+    pub fn client_imports_js(&self) -> String {
+        self.imports
+            .iter()
+            .filter(|import| match import {
+                ImportType::Bare(_) => true,
+                _ => import.bindings().iter().any(|binding| {
+                    self.islands
+                        .iter()
+                        .any(|island| &island.component == binding)
+                }),
+            })
+            .map(ImportType::to_statement)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// The islands as a JavaScript array expression.
+    pub fn islands_js(&self) -> String {
+        let islands: Vec<_> = self
+            .islands
+            .iter()
+            .map(|island| format!("  {},\n", island.to_js()))
+            .collect();
+        format!("[\n{}]", islands.concat())
+    }
+
+    /// The used components the server entry imports as a JavaScript array expression.
+    pub fn components_js(&self) -> String {
+        let client_only: Vec<_> = self
+            .imports
+            .iter()
+            .filter(|import| import.rendering() == Rendering::Client)
+            .flat_map(ImportType::bindings)
+            .collect();
+        let components: Vec<_> = self
+            .components
+            .iter()
+            .filter(|component| !client_only.contains(component))
+            .map(String::as_str)
+            .collect();
+        format!("[{}]", components.join(", "))
+    }
+
+    /// The `title` frontmatter field, falling back to the first top-level heading.
+    pub fn title(&self) -> Option<&str> {
+        self.frontmatter
+            .get("title")
+            .map(String::as_str)
+            .or_else(|| {
+                self.headings
+                    .iter()
+                    .find(|heading| heading.level == 1)
+                    .map(|heading| heading.text.as_str())
+            })
+    }
+}
+
+/// Generates a page's markup. Images found in `images`, keyed by their URL, are shown
+/// from their processed files; any others are left pointing where they were written.
+pub fn generate_html(
+    ast: &[ASTNode],
+    components: &ComponentMap,
+    images: &BTreeMap<String, ProcessedImage>,
+) -> GeneratedPage {
+    let mut page = GeneratedPage::default();
+    let mut renderings: BTreeMap<String, Rendering> = BTreeMap::new();
+    page.loader = ast.iter().find_map(|node| match node {
+        ASTNode::Import(import) if import.bindings().iter().any(|b| b == LOADER_EXPORT) => {
+            Some(import.source().to_string())
+        }
+        _ => None,
+    });
+    // Spreads the props the page's `getStaticProps` returned into its components
+    let static_props = match page.loader {
+        Some(_) => format!(" {{...(globalThis as any).{}}}", STATIC_PROPS_GLOBAL),
+        None => String::new(),
+    };
+    let mut mapped_elements = Vec::new();
+    let mut tag = |element: &str| mapped_tag(components, &mut mapped_elements, element);
+    let html = &mut page.markup;
+
+    for node in ast {
+        match node {
+            ASTNode::Frontmatter(frontmatter) => {
+                page.frontmatter = frontmatter.clone();
+            }
+            ASTNode::Import(import_type) => {
+                for binding in import_type.bindings() {
+                    if binding == LOADER_EXPORT {
+                        continue;
+                    }
+                    renderings.insert(binding.clone(), import_type.rendering());
+                    if !page.components.contains(&binding) {
+                        page.components.push(binding);
+                    }
+                }
+                page.imports.push(import_type.clone());
+            }
+
+            ASTNode::Heading(level, content) => {
+                let id = unique_heading_id(&page.headings, content);
+                let heading = tag(&format!("h{}", level));
+                html.push_str(&format!(
+                    "<{} id=\"{}\">{}</{}>\n",
+                    heading, id, content, heading
+                ));
+                page.headings.push(Heading {
+                    level: *level,
+                    text: content.clone(),
+                    id,
+                });
+            }
+            ASTNode::Paragraph(content) => {
+                let p = tag("p");
+                html.push_str(&format!("<{}>{}</{}>\n", p, content, p));
+            }
+            ASTNode::CodeBlock(content, lang) => {
+                let (pre, code) = (tag("pre"), tag("code"));
+                html.push_str(&format!(
+                    "<{}><{} className=\"language-{}\">{}</{}></{}>\n",
+                    pre, code, lang, content, code, pre
+                ));
+            }
+            ASTNode::Text(content) => {
+                html.push_str(&format!("{}\n", content));
+            }
+            ASTNode::Strong(content) => {
+                let strong = tag("strong");
+                html.push_str(&format!("<{}>{}</{}>", strong, content, strong));
+            }
+            ASTNode::Emphasis(content) => {
+                let em = tag("em");
+                html.push_str(&format!("<{}>{}</{}>", em, content, em));
+            }
+            ASTNode::Code(content) => {
+                let code = tag("code");
+                html.push_str(&format!("<{}>{}</{}>", code, content, code));
+            }
+            ASTNode::Link(text, url) => {
+                if url.trim().is_empty() {
+                    page.diagnostics.push(Diagnostic::warning(format!(
+                        "Link `{}` has an empty URL",
+                        text
+                    )));
+                }
+                let a = tag("a");
+                html.push_str(&format!("<{} href=\"{}\">{}</{}>", a, url, text, a));
+            }
+            ASTNode::Image(alt_text, url) => match images.get(url) {
+                Some(image) => {
+                    html.push_str(&image.to_jsx(&tag("img"), alt_text));
+                    if !page.images.iter().any(|shown| shown.source == image.source) {
+                        page.images.push(image.clone());
+                    }
+                }
+                None => html.push_str(&format!(
+                    "<{} src=\"{}\" alt=\"{}\" />",
+                    tag("img"),
+                    url,
+                    alt_text
+                )),
+            },
+            ASTNode::List(items) => {
+                let (ul, li) = (tag("ul"), tag("li"));
+                html.push_str(&format!("<{}>\n", ul));
+                for item in items {
+                    html.push_str(&format!("  <{}>{}</{}>\n", li, item, li));
+                }
+                html.push_str(&format!("</{}>\n", ul));
+            }
+            ASTNode::BlockQuote(content) => {
+                let blockquote = tag("blockquote");
+                html.push_str(&format!("<{}>{}</{}>\n", blockquote, content, blockquote));
+            }
+            ASTNode::ReactComponent(component_name, props, hydration) => {
+                if !page.components.contains(component_name) {
+                    page.components.push(component_name.clone());
+                }
+                let attributes: String = props
+                    .iter()
+                    .map(|prop| format!(" {}", prop.to_jsx()))
+                    .collect();
+                let rendering = renderings.get(component_name).copied().unwrap_or_default();
+                let hydration = match component_hydration(component_name, rendering, hydration) {
+                    Ok(hydration) => hydration,
+                    Err(diagnostic) => {
+                        page.diagnostics.push(diagnostic);
+                        continue;
+                    }
+                };
+                if hydration == Hydration::None {
+                    html.push_str(&format!(
+                        "<{}{}{} />\n",
+                        component_name, static_props, attributes
+                    ));
+                    continue;
+                }
+                let island = Island {
+                    id: page.islands.len(),
+                    component: component_name.clone(),
+                    props: props.clone(),
+                    hydration,
+                };
+                html.push_str(&island.to_jsx(&static_props));
+                html.push('\n');
+                page.islands.push(island);
+            }
+            ASTNode::Whitespace(_) => {}
+        }
+    }
+
+    // Islands read the static props from a single script rather than each carrying them
+    if page.loader.is_some() && !page.islands.is_empty() {
+        page.markup.insert_str(
+            0,
+            &format!(
+                "<script type=\"application/json\" data-static-props=\"\" \
+                 dangerouslySetInnerHTML={{{{ __html: (globalThis as any).{}.serialize(\
+                 (globalThis as any).{}, \"returned by {}\") }}}} />\n",
+                PROPS_GLOBAL, STATIC_PROPS_GLOBAL, LOADER_EXPORT
+            ),
+        );
+    }
+
+    // Mapped elements need their components imported into both entry files
+    for mapping in mapped_elements {
+        if !page.components.contains(&mapping.component) {
+            page.imports.push(ImportType::Default(
+                mapping.component.clone(),
+                mapping.path.clone(),
+            ));
+            page.components.push(mapping.component.clone());
+        }
+    }
+
+    page
+}
+
+/// How an instance of `component`, rendered as `rendering`, hydrates given the directive
+/// written on it.
+fn component_hydration(
+    component: &str,
+    rendering: Rendering,
+    directive: &Option<Hydration>,
+) -> Result<Hydration, Diagnostic> {
+    match (rendering, directive) {
+        (Rendering::Universal, directive) => Ok(directive.clone().unwrap_or_default()),
+        (Rendering::Server, None | Some(Hydration::None)) => Ok(Hydration::None),
+        (Rendering::Client, None) => Ok(Hydration::Only(None)),
+        (Rendering::Client, Some(only @ Hydration::Only(_))) => Ok(only.clone()),
+        (Rendering::Server, Some(directive)) => Err(Diagnostic::error(format!(
+            "<{}> is server-only, so `{}` can't hydrate it",
+            component,
+            directive.directive()
+        ))
+        .with_help("remove the directive, or drop `.server` from the module's name")),
+        (Rendering::Client, Some(directive)) => Err(Diagnostic::error(format!(
+            "<{}> is client-only, so it can't be rendered on the server for `{}`",
+            component,
+            directive.directive()
+        ))
+        .with_help(
+            "remove the directive, or write `client:only=\"Loading…\"` to show fallback text",
+        )),
+    }
+}
+
+fn mapped_tag<'a>(
+    components: &'a ComponentMap,
+    used: &mut Vec<&'a ComponentMapping>,
+    element: &str,
+) -> String {
+    match components.get(element) {
+        Some(mapping) => {
+            if !used.iter().any(|m| m.element == mapping.element) {
+                used.push(mapping);
+            }
+            mapping.component.clone()
+        }
+        None => element.to_string(),
+    }
+}
+
+/// Slugifies a heading, suffixing it when an earlier heading already took the id.
+fn unique_heading_id(headings: &[Heading], text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars() {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if (c.is_whitespace() || c == '-' || c == '_') && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_matches('-').to_string();
+    let slug = if slug.is_empty() {
+        "section".to_string()
+    } else {
+        slug
+    };
+
+    let mut id = slug.clone();
+    let mut suffix = 1;
+    while headings.iter().any(|heading| heading.id == id) {
+        id = format!("{}-{}", slug, suffix);
+        suffix += 1;
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse_markdown;
+
+    fn generate(source: &str) -> GeneratedPage {
+        let ast = parse_markdown(source).unwrap();
+        generate_html(&ast, &ComponentMap::default(), &BTreeMap::new())
+    }
+
+    #[test]
+    fn gives_headings_ids() {
+        let page = generate("# Hello, World!\n\n## Getting started_now\n\nText\n");
+        assert!(page
+            .markup
+            .contains("<h1 id=\"hello-world\">Hello, World!</h1>"));
+        assert!(page
+            .markup
+            .contains("<h2 id=\"getting-started-now\">Getting started_now</h2>"));
+        assert_eq!(page.headings[1].level, 2);
+        assert_eq!(page.headings[1].id, "getting-started-now");
+    }
+
+    #[test]
+    fn suffixes_repeated_heading_ids() {
+        let page = generate("## Usage\n\n## Usage\n\n## Usage\n\n## ???\n\nText\n");
+        let ids: Vec<_> = page.headings.iter().map(|h| h.id.as_str()).collect();
+        assert_eq!(ids, ["usage", "usage-1", "usage-2", "section"]);
+    }
+
+    #[test]
+    fn reads_frontmatter() {
+        let page = generate(
+            "---\ntitle: \"Hello: again\"\n# a comment\nloader: ./data.ts\n---\n# Heading\n",
+        );
+        assert_eq!(page.frontmatter["title"], "Hello: again");
+        assert_eq!(page.frontmatter["loader"], "./data.ts");
+        assert_eq!(page.frontmatter.len(), 2);
+        assert_eq!(page.title(), Some("Hello: again"));
+    }
+
+    #[test]
+    fn titles_pages_without_frontmatter_by_their_first_heading() {
+        let page = generate("## Intro\n\n# Main\n\nText\n");
+        assert!(page.frontmatter.is_empty());
+        assert_eq!(page.title(), Some("Main"));
+    }
+}
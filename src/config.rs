@@ -0,0 +1,42 @@
+use std::fs;
+use std::io;
+
+use serde::Deserialize;
+
+use crate::error::{Error, Result};
+use crate::images::ImageConfig;
+use crate::project::Project;
+use crate::public::PublicConfig;
+use crate::sizes::BudgetConfig;
+
+pub const CONFIG_FILE: &str = "kaffe.toml";
+
+/// Settings read from `kaffe.toml` in the project root. Every section is optional.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Free-form values exposed to `template.html` as `site`.
+    pub site: toml::Table,
+    /// How images shown by pages are resized and encoded.
+    pub images: ImageConfig,
+    /// What's copied from `public/` and how.
+    pub public: PublicConfig,
+    /// How large each page's client files may get.
+    pub budgets: BudgetConfig,
+}
+
+impl Config {
+    pub fn load(project: &Project) -> Result<Self> {
+        let path = project.path(CONFIG_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(Error::io(&path, e)),
+        };
+
+        toml::from_str(&contents).map_err(|e| Error::Config {
+            file: path,
+            message: e.to_string(),
+        })
+    }
+}
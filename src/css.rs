@@ -0,0 +1,678 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::CharIndices;
+
+use crate::imports::specifier_between;
+use crate::resolve::ImportResolver;
+use crate::sass::is_sass;
+
+/// Bytes at the start of a page's server-rendered HTML treated as above the fold, about
+/// what arrives in the first round trips.
+const ABOVE_THE_FOLD: usize = 14 * 1024;
+
+/// Elements outside the server-rendered content that every page has.
+const DOCUMENT_ELEMENTS: &[&str] = &["html", "body", "*"];
+
+/// The stylesheets reachable from `modules`, in the order the bundler includes them:
+/// depth first through the imports of each module, in import order. Sass files are
+/// listed by their source, and the files they `@use` are left to the compiler.
+pub fn stylesheets(modules: &[PathBuf], resolver: &ImportResolver) -> Vec<PathBuf> {
+    let mut seen = BTreeSet::new();
+    let mut sheets = Vec::new();
+    for module in modules {
+        visit(module, resolver, &mut seen, &mut sheets);
+    }
+    sheets
+}
+
+fn visit(
+    file: &Path,
+    resolver: &ImportResolver,
+    seen: &mut BTreeSet<PathBuf>,
+    sheets: &mut Vec<PathBuf>,
+) {
+    if !seen.insert(file.to_path_buf()) {
+        return;
+    }
+    if file.extension().is_some_and(|ext| ext == "css") || is_sass(file) {
+        sheets.push(file.to_path_buf());
+        return;
+    }
+    let Ok(contents) = fs::read_to_string(file) else {
+        return;
+    };
+    for import in resolver.imports_of(file, &contents) {
+        visit(&import, resolver, seen, sheets);
+    }
+}
+
+/// Stylesheets loaded by more than one page, in the order they first appear.
+pub fn shared_stylesheets<'a>(pages: impl IntoIterator<Item = &'a [PathBuf]>) -> Vec<PathBuf> {
+    let mut order = Vec::new();
+    let mut counts: BTreeMap<&Path, usize> = BTreeMap::new();
+    for sheets in pages {
+        for sheet in sheets {
+            let count = counts.entry(sheet).or_default();
+            if *count == 0 {
+                order.push(sheet);
+            }
+            *count += 1;
+        }
+    }
+    order
+        .into_iter()
+        .filter(|sheet| counts[sheet.as_path()] > 1)
+        .cloned()
+        .collect()
+}
+
+/// A stylesheet in `dir` that imports each of `sheets` in order, for the bundler to
+/// combine into one file.
+pub fn import_sheet(dir: &Path, sheets: &[PathBuf]) -> String {
+    sheets
+        .iter()
+        .map(|sheet| format!("@import \"{}\";\n", specifier_between(dir, sheet)))
+        .collect()
+}
+
+/// The rules of `css` that style the top of `html`: those with a selector whose last
+/// part names only tags, classes and ids found there. Media and support queries are kept
+/// around the rules inside them that match, as are font faces. Animations and other
+/// at-rules are left to the full stylesheet.
+pub fn critical_css(css: &str, html: &str) -> String {
+    let mut end = html.len().min(ABOVE_THE_FOLD);
+    while !html.is_char_boundary(end) {
+        end -= 1;
+    }
+    let used = UsedSelectors::scan(&html[..end]);
+
+    let mut critical = String::new();
+    keep_rules(css, &used, &mut critical);
+    // The result is inlined into a `<style>` element, which mustn't be closed early
+    critical.replace("</style", "<\\/style")
+}
+
+/// The tags, classes and ids in a piece of HTML.
+#[derive(Debug, Default)]
+struct UsedSelectors {
+    tags: HashSet<String>,
+    classes: HashSet<String>,
+    ids: HashSet<String>,
+}
+
+impl UsedSelectors {
+    fn scan(html: &str) -> Self {
+        let mut used = Self::default();
+        let mut rest = html;
+        while let Some(start) = rest.find('<') {
+            rest = &rest[start + 1..];
+            let name: String = rest
+                .chars()
+                .take_while(|c| c.is_ascii_alphanumeric() || *c == '-')
+                .collect();
+            if name.is_empty() {
+                continue;
+            }
+            let tag_end = tag_end(rest);
+            let tag = &rest[..tag_end];
+            used.tags.insert(name.to_ascii_lowercase());
+            if let Some(classes) = attribute(tag, "class") {
+                used.classes.extend(
+                    decode_entities(classes)
+                        .split_whitespace()
+                        .map(str::to_string),
+                );
+            }
+            if let Some(id) = attribute(tag, "id") {
+                used.ids.insert(decode_entities(id));
+            }
+            rest = &rest[tag_end..];
+        }
+        used
+    }
+
+    fn matches(&self, selectors: &str) -> bool {
+        split_top_level(selectors, ',')
+            .iter()
+            .any(|selector| self.matches_compound(last_compound(selector.trim())))
+    }
+
+    fn matches_compound(&self, compound: &str) -> bool {
+        let (tag, classes, ids) = parse_compound(compound);
+        let tag = tag.to_ascii_lowercase();
+        (tag.is_empty() || DOCUMENT_ELEMENTS.contains(&tag.as_str()) || self.tags.contains(&tag))
+            && classes.iter().all(|class| self.classes.contains(class))
+            && ids.iter().all(|id| self.ids.contains(id))
+    }
+}
+
+/// Where the tag `rest` starts with ends: its first `>` outside of a quoted attribute.
+fn tag_end(rest: &str) -> usize {
+    let mut quote = None;
+    for (i, c) in rest.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (_, Some(open)) if c == open => quote = None,
+            ('>', None) => return i,
+            _ => {}
+        }
+    }
+    rest.len()
+}
+
+/// Undoes the escaping React applies to attribute values.
+fn decode_entities(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#x27;", "'")
+        .replace("&amp;", "&")
+}
+
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let start = tag.find(&format!(" {}=\"", name))? + name.len() + 3;
+    let value = &tag[start..];
+    Some(&value[..value.find('"')?])
+}
+
+fn keep_rules(css: &str, used: &UsedSelectors, output: &mut String) {
+    for (prelude, body) in split_rules(css) {
+        let prelude = prelude.trim();
+        let Some(body) = body else {
+            // Statements like `@charset`
+            output.push_str(prelude);
+            output.push(';');
+            continue;
+        };
+
+        if let Some(at_rule) = prelude.strip_prefix('@') {
+            match at_rule_name(at_rule) {
+                "media" | "supports" | "layer" => {
+                    let mut inner = String::new();
+                    keep_rules(body, used, &mut inner);
+                    if !inner.is_empty() {
+                        output.push_str(&format!("{}{{{}}}", prelude, inner));
+                    }
+                }
+                "font-face" => output.push_str(&format!("{}{{{}}}", prelude, body.trim())),
+                _ => {}
+            }
+        } else if used.matches(prelude) {
+            output.push_str(&format!("{}{{{}}}", prelude, body.trim()));
+        }
+    }
+}
+
+/// Renames the classes in the selectors of `css` with `rename`, the way CSS modules scope
+/// them to the file they're declared in. Returns the new stylesheet along with the name
+/// each class was given.
+pub fn scope_classes(
+    css: &str,
+    rename: &dyn Fn(&str) -> String,
+) -> (String, BTreeMap<String, String>) {
+    let mut names = BTreeMap::new();
+    let mut output = String::new();
+    scope_rules(css, rename, &mut names, &mut output);
+    (output, names)
+}
+
+fn scope_rules(
+    css: &str,
+    rename: &dyn Fn(&str) -> String,
+    names: &mut BTreeMap<String, String>,
+    output: &mut String,
+) {
+    for (prelude, body) in split_rules(css) {
+        let prelude = prelude.trim();
+        let Some(body) = body else {
+            output.push_str(prelude);
+            output.push(';');
+            continue;
+        };
+
+        if let Some(at_rule) = prelude.strip_prefix('@') {
+            match at_rule_name(at_rule) {
+                "media" | "supports" | "layer" | "container" => {
+                    output.push_str(prelude);
+                    output.push('{');
+                    scope_rules(body, rename, names, output);
+                    output.push('}');
+                }
+                // Keyframe selectors and font faces have no classes
+                _ => output.push_str(&format!("{}{{{}}}", prelude, body)),
+            }
+        } else {
+            output.push_str(&scope_selector(prelude, rename, names));
+            output.push_str(&format!("{{{}}}", body));
+        }
+    }
+}
+
+fn scope_selector(
+    selector: &str,
+    rename: &dyn Fn(&str) -> String,
+    names: &mut BTreeMap<String, String>,
+) -> String {
+    let mut output = String::new();
+    let mut chars = selector.chars().peekable();
+    let mut brackets = 0;
+
+    while let Some(c) = chars.next() {
+        output.push(c);
+        match c {
+            '\\' => output.extend(chars.next()),
+            // Attribute values can contain dots that aren't classes
+            '[' => brackets += 1,
+            ']' => brackets -= 1,
+            '.' if brackets == 0
+                && chars
+                    .peek()
+                    .is_some_and(|&c| c.is_alphabetic() || c == '_' || c == '-' || c == '\\') =>
+            {
+                let mut class = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c == '\\' {
+                        chars.next();
+                        class.extend(chars.next());
+                    } else if c.is_alphanumeric() || c == '_' || c == '-' {
+                        chars.next();
+                        class.push(c);
+                    } else {
+                        break;
+                    }
+                }
+                let scoped = names.entry(class).or_insert_with_key(|class| rename(class));
+                output.push_str(scoped);
+            }
+            _ => {}
+        }
+    }
+
+    output
+}
+
+/// The name of an at-rule, given the text after its `@`.
+fn at_rule_name(at_rule: &str) -> &str {
+    at_rule
+        .split(|c: char| c.is_whitespace() || c == '(')
+        .next()
+        .unwrap_or_default()
+}
+
+/// Splits a stylesheet into its top-level statements and rules, as the text before each
+/// `;` or `{` and the body of the block if there is one. Comments outside of blocks are
+/// dropped, and bodies are left as written.
+fn split_rules(css: &str) -> Vec<(String, Option<&str>)> {
+    let mut rules = Vec::new();
+    let mut prelude = String::new();
+    let mut chars = css.char_indices().peekable();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '/' if chars.peek().is_some_and(|(_, next)| *next == '*') => {
+                chars.next();
+                skip_comment(&mut chars);
+            }
+            '"' | '\'' => {
+                prelude.push(c);
+                prelude.push_str(skip_string(css, &mut chars, c));
+            }
+            ';' => {
+                if !prelude.trim().is_empty() {
+                    rules.push((std::mem::take(&mut prelude), None));
+                }
+                prelude.clear();
+            }
+            '{' => {
+                let start = i + 1;
+                let mut depth = 1;
+                let mut end = css.len();
+                while let Some((j, inner)) = chars.next() {
+                    match inner {
+                        '/' if chars.peek().is_some_and(|(_, next)| *next == '*') => {
+                            chars.next();
+                            skip_comment(&mut chars);
+                        }
+                        '"' | '\'' => {
+                            skip_string(css, &mut chars, inner);
+                        }
+                        '{' => depth += 1,
+                        '}' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                end = j;
+                                break;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                rules.push((std::mem::take(&mut prelude), Some(&css[start..end])));
+            }
+            _ => prelude.push(c),
+        }
+    }
+
+    rules
+}
+
+/// Consumes the rest of a string in `css` opened with `quote`, returning it along with
+/// the closing quote.
+fn skip_string<'a>(css: &'a str, chars: &mut Peekable<CharIndices<'a>>, quote: char) -> &'a str {
+    let start = chars.peek().map_or(css.len(), |(i, _)| *i);
+    let mut end = css.len();
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            chars.next();
+        } else if c == quote {
+            end = i + c.len_utf8();
+            break;
+        }
+    }
+    &css[start..end]
+}
+
+fn skip_comment(chars: &mut Peekable<CharIndices>) {
+    let mut previous = ' ';
+    for (_, c) in chars.by_ref() {
+        if previous == '*' && c == '/' {
+            break;
+        }
+        previous = c;
+    }
+}
+
+/// Splits `text` on `separator` outside of parentheses and brackets, and not escaped.
+fn split_top_level(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            _ if c == separator && depth == 0 => {
+                parts.push(&text[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+/// The part of a selector after its last combinator, which names the element styled.
+fn last_compound(selector: &str) -> &str {
+    let mut depth = 0;
+    let mut start = 0;
+    let mut chars = selector.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ' ' | '>' | '+' | '~' if depth == 0 => start = i + 1,
+            _ => {}
+        }
+    }
+    &selector[start..]
+}
+
+/// The tag, classes and ids in a compound selector, ignoring attribute selectors and
+/// pseudo-classes.
+fn parse_compound(compound: &str) -> (String, Vec<String>, Vec<String>) {
+    let mut tag = String::new();
+    let mut classes = Vec::new();
+    let mut ids = Vec::new();
+    let mut current: Option<(char, String)> = None;
+    let mut chars = compound.chars();
+    let mut depth = 0;
+
+    let finish = |current: &mut Option<(char, String)>,
+                  classes: &mut Vec<String>,
+                  ids: &mut Vec<String>| match current.take() {
+        Some(('.', name)) => classes.push(name),
+        Some(('#', name)) => ids.push(name),
+        _ => {}
+    };
+
+    while let Some(c) = chars.next() {
+        if depth > 0 {
+            match c {
+                '(' | '[' => depth += 1,
+                ')' | ']' => depth -= 1,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '\\' => {
+                let escaped = chars.next().unwrap_or_default();
+                match &mut current {
+                    Some((_, name)) => name.push(escaped),
+                    None => tag.push(escaped),
+                }
+            }
+            '.' | '#' => {
+                finish(&mut current, &mut classes, &mut ids);
+                current = Some((c, String::new()));
+            }
+            '[' | '(' => {
+                finish(&mut current, &mut classes, &mut ids);
+                depth += 1;
+            }
+            ':' => {
+                finish(&mut current, &mut classes, &mut ids);
+                // Pseudo-classes and elements don't change whether the element exists
+                current = Some((':', String::new()));
+            }
+            _ => match &mut current {
+                Some((_, name)) => name.push(c),
+                None => tag.push(c),
+            },
+        }
+    }
+    finish(&mut current, &mut classes, &mut ids);
+
+    (tag, classes, ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renames the way CSS modules do, keeping the result a valid identifier.
+    fn scoped(class: &str) -> String {
+        let class: String = class
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        format!("s_{}", class)
+    }
+
+    #[test]
+    fn keeps_rules_for_elements_above_the_fold() {
+        let css = "h1 { margin: 0 } .card { color: red } .missing { color: blue } \
+                   #hero > .title { font-weight: bold } @keyframes spin { from { opacity: 0 } }";
+        let html = r#"<h1 id="hero"><span class="title card">Hi</span></h1>"#;
+        assert_eq!(
+            critical_css(css, html),
+            "h1{margin: 0}.card{color: red}#hero > .title{font-weight: bold}"
+        );
+    }
+
+    #[test]
+    fn matches_escaped_selectors() {
+        let css = r".md\:flex { display: flex } .w-1\/2 { width: 50% } .other\:x { color: red }";
+        let html = r#"<div class="md:flex w-1/2"></div>"#;
+        assert_eq!(
+            critical_css(css, html),
+            r".md\:flex{display: flex}.w-1\/2{width: 50%}"
+        );
+    }
+
+    #[test]
+    fn matches_escaped_combinator_characters() {
+        let css = r".a\ b { color: red } .x\>y { color: blue } .p\,q { color: green }";
+        assert_eq!(critical_css(css, r#"<div class="b y q"></div>"#), "");
+        assert_eq!(
+            critical_css(css, r#"<div class="x&gt;y p,q"></div>"#),
+            r".x\>y{color: blue}.p\,q{color: green}"
+        );
+    }
+
+    #[test]
+    fn reads_attributes_containing_angle_brackets() {
+        let css = ".after { color: red }";
+        let html = r#"<a title="1 > 0" class="after">Link</a>"#;
+        assert_eq!(critical_css(css, html), ".after{color: red}");
+    }
+
+    #[test]
+    fn skips_braces_in_strings() {
+        let css = r#"p::after { content: "{ not a block }" } [data-x="{"] { color: red } .gone { color: blue }"#;
+        let html = "<p data-x=\"{\">Text</p>";
+        assert_eq!(
+            critical_css(css, html),
+            r#"p::after{content: "{ not a block }"}[data-x="{"]{color: red}"#
+        );
+    }
+
+    #[test]
+    fn keeps_matching_rules_inside_nested_at_rules() {
+        let css =
+            "@media (min-width: 600px) { @supports (display: grid) { .grid { display: grid } \
+                   .unused { color: red } } .nav { color: blue } } \
+                   @media print { .unused { display: none } } \
+                   @font-face { font-family: Inter; src: url(inter.woff2) }";
+        let html = r#"<nav class="nav"><div class="grid"></div></nav>"#;
+        assert_eq!(
+            critical_css(css, html),
+            "@media (min-width: 600px){@supports (display: grid){.grid{display: grid}}.nav{color: blue}}\
+             @font-face{font-family: Inter; src: url(inter.woff2)}"
+        );
+    }
+
+    #[test]
+    fn escapes_closing_style_tags() {
+        let css = r#"p::before { content: "</style><script>" }"#;
+        assert_eq!(
+            critical_css(css, "<p>Text</p>"),
+            r#"p::before{content: "<\/style><script>"}"#
+        );
+    }
+
+    #[test]
+    fn ignores_classes_inside_pseudo_classes() {
+        let css = ".card:not(.active) { opacity: 0.5 } :not(.x) { color: red } .card:is(.a, .b) { color: blue }";
+        let html = r#"<div class="card"></div>"#;
+        assert_eq!(
+            critical_css(css, html),
+            ".card:not(.active){opacity: 0.5}:not(.x){color: red}.card:is(.a, .b){color: blue}"
+        );
+    }
+
+    #[test]
+    fn drops_comments() {
+        let css = "/* .card { color: red } */ .card { /* inside */ color: blue }";
+        assert_eq!(
+            critical_css(css, r#"<div class="card"></div>"#),
+            ".card{/* inside */ color: blue}"
+        );
+    }
+
+    #[test]
+    fn scopes_classes_in_selectors() {
+        let (css, names) = scope_classes(
+            ".card, .card > .title:hover { color: red } a.link { color: blue }",
+            &scoped,
+        );
+        assert_eq!(
+            css,
+            ".s_card, .s_card > .s_title:hover{ color: red }a.s_link{ color: blue }"
+        );
+        assert_eq!(names["card"], "s_card");
+        assert_eq!(names["title"], "s_title");
+        assert_eq!(names["link"], "s_link");
+    }
+
+    #[test]
+    fn scopes_escaped_classes() {
+        let (css, names) = scope_classes(r".md\:flex { display: flex }", &scoped);
+        assert_eq!(css, ".s_md_flex{ display: flex }");
+        assert_eq!(names["md:flex"], "s_md_flex");
+    }
+
+    #[test]
+    fn scopes_classes_inside_pseudo_classes() {
+        let (css, names) = scope_classes(".card:not(.active) { opacity: 0.5 }", &scoped);
+        assert_eq!(css, ".s_card:not(.s_active){ opacity: 0.5 }");
+        assert_eq!(names.len(), 2);
+    }
+
+    #[test]
+    fn leaves_strings_numbers_and_attributes_alone() {
+        let (css, names) = scope_classes(
+            r#"[data-file="a.b"] .icon { content: ".x { }"; width: 1.5em }"#,
+            &scoped,
+        );
+        assert_eq!(
+            css,
+            r#"[data-file="a.b"] .s_icon{ content: ".x { }"; width: 1.5em }"#
+        );
+        assert_eq!(names.keys().collect::<Vec<_>>(), ["icon"]);
+    }
+
+    #[test]
+    fn scopes_nested_at_rules_but_not_keyframes() {
+        let (css, _) = scope_classes(
+            "@media (min-width: 1.5em) { @supports (gap: 1px) { .grid { gap: 1px } } } \
+             @keyframes fade { from { opacity: 0 } to { opacity: 1 } }",
+            &scoped,
+        );
+        assert_eq!(
+            css,
+            "@media (min-width: 1.5em){@supports (gap: 1px){.s_grid{ gap: 1px }}}\
+             @keyframes fade{ from { opacity: 0 } to { opacity: 1 } }"
+        );
+    }
+
+    #[test]
+    fn parses_compound_selectors() {
+        assert_eq!(
+            parse_compound("a.link#top:hover[href]"),
+            (
+                "a".to_string(),
+                vec!["link".to_string()],
+                vec!["top".to_string()]
+            )
+        );
+        assert_eq!(
+            parse_compound(r".md\:flex"),
+            (String::new(), vec!["md:flex".to_string()], Vec::new())
+        );
+    }
+
+    #[test]
+    fn finds_the_last_compound() {
+        assert_eq!(last_compound("nav > ul li.item"), "li.item");
+        assert_eq!(last_compound("a:not(.b > .c)"), "a:not(.b > .c)");
+        assert_eq!(last_compound(r".a\ b"), r".a\ b");
+    }
+}
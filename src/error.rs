@@ -0,0 +1,143 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::diagnostic::Diagnostic;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A failure at one stage of building a page, tagged with the file it happened in.
+#[derive(Debug)]
+pub enum Error {
+    /// The Markdown source could not be parsed.
+    Parse { file: PathBuf, message: String },
+    /// Code generation or validation reported errors.
+    Codegen {
+        file: PathBuf,
+        diagnostics: Vec<Diagnostic>,
+    },
+    /// The client or server bundle could not be built.
+    Bundle { file: PathBuf, message: String },
+    /// The server bundle failed while rendering the page.
+    Ssr { file: PathBuf, message: String },
+    /// An entry or HTML template is missing, invalid or failed to render.
+    Template { file: PathBuf, message: String },
+    /// `kaffe.toml` could not be read or parsed.
+    Config { file: PathBuf, message: String },
+    /// The page's client files are larger than the `[budgets]` in `kaffe.toml` allow.
+    Budget { file: PathBuf, message: String },
+    /// The page's `getStaticProps` threw, returned something other than `{ props }` or
+    /// never settled.
+    Loader { file: PathBuf, message: String },
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+}
+
+impl Error {
+    pub fn io(path: impl AsRef<Path>, source: std::io::Error) -> Self {
+        Error::Io {
+            path: path.as_ref().to_path_buf(),
+            source,
+        }
+    }
+
+    /// The page or file the error is attributed to.
+    pub fn file(&self) -> &Path {
+        match self {
+            Error::Parse { file, .. }
+            | Error::Codegen { file, .. }
+            | Error::Bundle { file, .. }
+            | Error::Ssr { file, .. }
+            | Error::Template { file, .. }
+            | Error::Config { file, .. }
+            | Error::Budget { file, .. }
+            | Error::Loader { file, .. } => file,
+            Error::Io { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse { file, message } => {
+                write!(f, "failed to parse {}: {}", file.display(), message)
+            }
+            Error::Codegen { file, diagnostics } => {
+                let file_name = file.display().to_string();
+                let errors: Vec<_> = diagnostics.iter().filter(|d| d.is_error()).collect();
+                write!(f, "{} has {} error(s)", file_name, errors.len())?;
+                for diagnostic in errors {
+                    write!(f, "\n  {}", diagnostic.report(&file_name))?;
+                }
+                Ok(())
+            }
+            Error::Bundle { file, message } => {
+                write!(f, "failed to bundle {}: {}", file.display(), message)
+            }
+            Error::Ssr { file, message } => {
+                write!(f, "failed to render {}: {}", file.display(), message)
+            }
+            Error::Template { file, message } => {
+                write!(f, "template error in {}: {}", file.display(), message)
+            }
+            Error::Config { file, message } => {
+                write!(f, "invalid config {}: {}", file.display(), message)
+            }
+            Error::Budget { file, message } => {
+                write!(f, "{} is over budget: {}", file.display(), message)
+            }
+            Error::Loader { file, message } => {
+                write!(
+                    f,
+                    "getStaticProps failed for {}: {}",
+                    file.display(),
+                    message
+                )
+            }
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_only_errors_of_a_codegen_failure() {
+        let error = Error::Codegen {
+            file: PathBuf::from("pages/post.mdx"),
+            diagnostics: vec![
+                Diagnostic::warning("`Chart` is imported but never used"),
+                Diagnostic::error("Component `Chrt` is used but never imported"),
+            ],
+        };
+        assert_eq!(
+            error.to_string(),
+            "pages/post.mdx has 1 error(s)\n  \
+             pages/post.mdx: error: Component `Chrt` is used but never imported"
+        );
+    }
+
+    #[test]
+    fn attributes_errors_to_their_file() {
+        let error = Error::io(
+            "dist/post.html",
+            std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied"),
+        );
+        assert_eq!(error.file(), Path::new("dist/post.html"));
+        assert_eq!(error.to_string(), "dist/post.html: denied");
+        assert!(std::error::Error::source(&error).is_some());
+    }
+}
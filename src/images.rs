@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageReader};
+use serde::Deserialize;
+use tempfile::NamedTempFile;
+
+use crate::cache::hash_bytes;
+use crate::diagnostic::Diagnostic;
+use crate::parser::{ASTNode, Document};
+
+/// Directory under the static output directory that processed images are written to.
+pub const IMAGES_DIR: &str = "images";
+
+/// Hex digits of the content hash put in processed image names.
+const FINGERPRINT_LENGTH: usize = 8;
+
+/// The `[images]` table of `kaffe.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImageConfig {
+    /// Widths images are resized to. Images are never scaled up, and are always offered
+    /// at their own width too.
+    pub widths: Vec<u32>,
+    /// Encodings offered ahead of the image's own format, best first.
+    pub formats: Vec<ImageEncoding>,
+}
+
+impl Default for ImageConfig {
+    fn default() -> Self {
+        Self {
+            widths: vec![480, 960, 1440, 1920],
+            formats: vec![ImageEncoding::Avif, ImageEncoding::Webp],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageEncoding {
+    Avif,
+    Webp,
+}
+
+impl ImageEncoding {
+    fn format(&self) -> ImageFormat {
+        match self {
+            ImageEncoding::Avif => ImageFormat::Avif,
+            ImageEncoding::Webp => ImageFormat::WebP,
+        }
+    }
+}
+
+/// A local image a page shows, resized and encoded into the static directory.
+#[derive(Debug, Clone)]
+pub struct ProcessedImage {
+    /// The file the page refers to.
+    pub source: PathBuf,
+    /// Width and height in pixels, when the format has them.
+    pub size: Option<(u32, u32)>,
+    /// URL of the image at full size in its own format.
+    pub src: String,
+    /// The MIME type of each encoding offered, with the URL and width of every variant.
+    /// The image's own format comes last. Empty for images copied as they are.
+    pub sources: Vec<(&'static str, Vec<(String, u32)>)>,
+    /// Every file written for the image.
+    pub files: Vec<PathBuf>,
+}
+
+impl ProcessedImage {
+    /// JSX showing the image with `alt` text, using `img` as the image element.
+    pub fn to_jsx(&self, img: &str, alt: &str) -> String {
+        let mut attributes = format!("src=\"{}\" alt=\"{}\"", self.src, alt);
+        let sizes = self
+            .size
+            .map(|(width, _)| format!(" sizes=\"(max-width: {}px) 100vw, {}px\"", width, width));
+        if let (Some((_, srcset)), Some(sizes)) = (self.sources.last(), &sizes) {
+            attributes.push_str(&format!(
+                " srcSet=\"{}\"{}",
+                srcset_attribute(srcset),
+                sizes
+            ));
+        }
+        if let Some((width, height)) = self.size {
+            attributes.push_str(&format!(" width=\"{}\" height=\"{}\"", width, height));
+        }
+        attributes.push_str(" loading=\"lazy\" decoding=\"async\"");
+
+        let Some((_, modern)) = self.sources.split_last() else {
+            return format!("<{} {} />", img, attributes);
+        };
+        let sources: String = modern
+            .iter()
+            .map(|(mime, srcset)| {
+                format!(
+                    "<source type=\"{}\" srcSet=\"{}\"{} />",
+                    mime,
+                    srcset_attribute(srcset),
+                    sizes.as_deref().unwrap_or_default()
+                )
+            })
+            .collect();
+        format!("<picture>{}<{} {} /></picture>", sources, img, attributes)
+    }
+}
+
+fn srcset_attribute(variants: &[(String, u32)]) -> String {
+    variants
+        .iter()
+        .map(|(url, width)| format!("{} {}w", url, width))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Writes the images pages show into the static directory. Every file is named after a
+/// hash of the original, so images already processed by an earlier build are reused.
+#[derive(Debug)]
+pub struct ImageProcessor {
+    static_dir: PathBuf,
+    public_dir: PathBuf,
+    config: ImageConfig,
+}
+
+impl ImageProcessor {
+    /// Writes into `static_dir`, looking for images with absolute URLs in `public_dir`
+    /// when the input directory doesn't have them.
+    pub fn new(
+        static_dir: impl Into<PathBuf>,
+        public_dir: impl Into<PathBuf>,
+        config: ImageConfig,
+    ) -> Self {
+        Self {
+            static_dir: static_dir.into(),
+            public_dir: public_dir.into(),
+            config,
+        }
+    }
+
+    /// Processes every local image in `document`, keyed by the URL it was written with.
+    /// URLs are relative to the page at `page`, or to `input_dir` or the public directory
+    /// when they start with `/`. Images that are missing or can't be read are reported
+    /// as errors.
+    pub fn process_page(
+        &self,
+        document: &Document,
+        page: &Path,
+        input_dir: &Path,
+    ) -> (BTreeMap<String, ProcessedImage>, Vec<Diagnostic>) {
+        let dir = page.parent().unwrap_or(input_dir);
+        let mut images = BTreeMap::new();
+        let mut diagnostics = Vec::new();
+
+        for (node, span) in document.iter() {
+            let ASTNode::Image(_, url) = node else {
+                continue;
+            };
+            if is_remote(url) || images.contains_key(url) {
+                continue;
+            }
+            let source = match url.strip_prefix('/') {
+                Some(path) if !input_dir.join(path).is_file() => self.public_dir.join(path),
+                Some(path) => input_dir.join(path),
+                None => dir.join(url),
+            };
+            if !source.is_file() {
+                diagnostics.push(
+                    Diagnostic::error(format!(
+                        "Image \"{}\" not found at {}",
+                        url,
+                        source.display()
+                    ))
+                    .with_span(*span),
+                );
+                continue;
+            }
+            match self.process(&source) {
+                Ok(image) => {
+                    images.insert(url.clone(), image);
+                }
+                Err(message) => diagnostics.push(
+                    Diagnostic::error(format!("Cannot process image \"{}\": {}", url, message))
+                        .with_span(*span),
+                ),
+            }
+        }
+
+        (images, diagnostics)
+    }
+
+    fn process(&self, source: &Path) -> Result<ProcessedImage, String> {
+        let bytes = fs::read(source).map_err(|e| e.to_string())?;
+        let stem = source
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("image");
+        let hash = &hash_bytes(&bytes)[..FINGERPRINT_LENGTH];
+        let dir = self.static_dir.join(IMAGES_DIR);
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let url = |name: &str| format!("/static/{}/{}", IMAGES_DIR, name);
+
+        let reader = ImageReader::new(Cursor::new(&bytes))
+            .with_guessed_format()
+            .map_err(|e| e.to_string())?;
+        let own_format = match reader.format() {
+            Some(format @ (ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)) => format,
+            // Anything else, like SVGs and animated GIFs, is copied as it is
+            _ => {
+                let extension = source
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .unwrap_or_default();
+                let name = format!("{}.{}.{}", stem, hash, extension);
+                let path = dir.join(&name);
+                if !path.is_file() {
+                    write_atomically(&path, |temp| {
+                        fs::write(temp, &bytes).map_err(|e| e.to_string())
+                    })?;
+                }
+                return Ok(ProcessedImage {
+                    source: source.to_path_buf(),
+                    size: reader.into_dimensions().ok(),
+                    src: url(&name),
+                    sources: Vec::new(),
+                    files: vec![path],
+                });
+            }
+        };
+
+        let image = reader.decode().map_err(|e| e.to_string())?;
+        let (width, height) = image.dimensions();
+        let mut widths: Vec<_> = self
+            .config
+            .widths
+            .iter()
+            .copied()
+            .filter(|&w| w < width)
+            .chain(std::iter::once(width))
+            .collect();
+        widths.sort_unstable();
+        widths.dedup();
+
+        let formats = self
+            .config
+            .formats
+            .iter()
+            .map(ImageEncoding::format)
+            .filter(|&format| format != own_format)
+            .chain(std::iter::once(own_format));
+        let mut sources = Vec::new();
+        let mut files = Vec::new();
+        for format in formats {
+            let extension = file_extension(format);
+            let mut variants = Vec::new();
+            for &variant_width in &widths {
+                let name = if variant_width == width {
+                    format!("{}.{}.{}", stem, hash, extension)
+                } else {
+                    format!("{}.{}-{}.{}", stem, hash, variant_width, extension)
+                };
+                let path = dir.join(&name);
+                if !path.is_file() {
+                    write_atomically(&path, |temp| {
+                        // The original is kept as it is rather than encoded again
+                        if format == own_format && variant_width == width {
+                            return fs::write(temp, &bytes).map_err(|e| e.to_string());
+                        }
+                        let resized = image.resize(variant_width, u32::MAX, FilterType::Lanczos3);
+                        let resized = if format == own_format {
+                            resized
+                        } else {
+                            DynamicImage::ImageRgba8(resized.to_rgba8())
+                        };
+                        resized
+                            .save_with_format(temp, format)
+                            .map_err(|e| e.to_string())
+                    })?;
+                }
+                variants.push((url(&name), variant_width));
+                files.push(path);
+            }
+            sources.push((format.to_mime_type(), variants));
+        }
+
+        Ok(ProcessedImage {
+            source: source.to_path_buf(),
+            size: Some((width, height)),
+            src: url(&format!("{}.{}.{}", stem, hash, file_extension(own_format))),
+            sources,
+            files,
+        })
+    }
+}
+
+fn file_extension(format: ImageFormat) -> &'static str {
+    format.extensions_str().first().copied().unwrap_or_default()
+}
+
+/// Whether `url` points somewhere other than a file in the project.
+fn is_remote(url: &str) -> bool {
+    url.contains("://") || url.starts_with("//") || url.starts_with("data:")
+}
+
+/// Writes `path` through a temporary file, so pages processing the same image at once
+/// never see it half written.
+fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&Path) -> Result<(), String>,
+) -> Result<(), String> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let temp = NamedTempFile::new_in(dir).map_err(|e| e.to_string())?;
+    write(temp.path())?;
+    temp.persist(path).map_err(|e| e.to_string())?;
+    Ok(())
+}
@@ -0,0 +1,103 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+/// Extensions tried, in order, when an import leaves its extension off.
+const RESOLVE_EXTENSIONS: &[&str] = &["tsx", "ts", "jsx", "js", "mjs", "json", "css"];
+
+/// Local files `file` imports, found by looking for relative string literals in its
+/// `contents`. Anything that looks like a relative import and resolves to a file counts;
+/// bare package imports are skipped.
+pub fn imports_of(file: &Path, contents: &str) -> Vec<PathBuf> {
+    relative_imports(file, contents)
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect()
+}
+
+/// Like [`imports_of`], but keeps the specifier each file was imported with.
+pub fn relative_imports<'a>(file: &Path, contents: &'a str) -> Vec<(&'a str, PathBuf)> {
+    let dir = file.parent().unwrap_or(Path::new(""));
+    relative_literals(contents)
+        .filter_map(|specifier| Some((specifier, resolve(dir, specifier)?)))
+        .collect()
+}
+
+/// The specifier that imports `target` from a file in `dir`. Both paths must be
+/// canonical.
+pub fn specifier_between(dir: &Path, target: &Path) -> String {
+    let common = dir
+        .components()
+        .zip(target.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let parents = dir.components().count() - common;
+    let rest = target
+        .components()
+        .skip(common)
+        .map(|c| c.as_os_str().to_string_lossy().into_owned());
+
+    let parts: Vec<_> = std::iter::repeat_n("..".to_string(), parents)
+        .chain(rest)
+        .collect();
+    if parents == 0 {
+        format!("./{}", parts.join("/"))
+    } else {
+        parts.join("/")
+    }
+}
+
+fn relative_literals(source: &str) -> impl Iterator<Item = &str> {
+    string_literals(source)
+        .filter(|literal| literal.starts_with("./") || literal.starts_with("../"))
+}
+
+/// Every single or double quoted string in `source`, in order. Strings don't span lines
+/// in the code this is used on, so each line is scanned on its own.
+pub fn string_literals(source: &str) -> impl Iterator<Item = &str> {
+    source.lines().flat_map(|line| {
+        let mut literals = Vec::new();
+        let mut rest = line;
+        while let Some(start) = rest.find(['"', '\'']) {
+            let quote = rest[start..].chars().next().unwrap_or('"');
+            let after = &rest[start + 1..];
+            let Some(end) = after.find(quote) else {
+                break;
+            };
+            literals.push(&after[..end]);
+            rest = &after[end + 1..];
+        }
+        literals
+    })
+}
+
+fn resolve(dir: &Path, specifier: &str) -> Option<PathBuf> {
+    if !(specifier.starts_with("./") || specifier.starts_with("../")) {
+        return None;
+    }
+    resolve_path(&dir.join(specifier))
+}
+
+/// Finds the file an import of `path` refers to the way the bundler would, trying
+/// [`RESOLVE_EXTENSIONS`] and then `index` files. The result is canonicalized so the same
+/// file is recorded once however it was reached.
+pub fn resolve_path(path: &Path) -> Option<PathBuf> {
+    let with_extension = |path: &Path, extension: &str| {
+        let mut name = OsString::from(path.as_os_str());
+        name.push(".");
+        name.push(extension);
+        PathBuf::from(name)
+    };
+    std::iter::once(path.to_path_buf())
+        .chain(
+            RESOLVE_EXTENSIONS
+                .iter()
+                .map(|extension| with_extension(path, extension)),
+        )
+        .chain(
+            RESOLVE_EXTENSIONS
+                .iter()
+                .map(|extension| with_extension(&path.join("index"), extension)),
+        )
+        .find(|candidate| candidate.is_file())
+        .and_then(|candidate| candidate.canonicalize().ok())
+}
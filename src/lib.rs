@@ -1,7 +1,31 @@
 use std::path::PathBuf;
 
+pub mod bundler;
+pub mod cache;
+pub mod codegen;
+pub mod config;
+pub mod css;
+pub mod diagnostic;
+pub mod error;
+pub mod images;
+pub mod imports;
+pub mod loader;
+pub mod manifest;
+pub mod mapping;
+pub mod mode;
+pub mod parallel;
 pub mod parser;
+pub mod project;
+pub mod public;
+pub mod resolve;
+pub mod sass;
+pub mod scratch;
+pub mod sizes;
+pub mod template;
 pub mod v8;
+pub mod validate;
+
+pub use error::{Error, Result};
 
 #[derive(Clone)]
 pub struct Kaffe {
@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+
+use crate::parser::Frontmatter;
+
+/// The export a page's loader module provides, and the binding a page imports it as.
+pub const LOADER_EXPORT: &str = "getStaticProps";
+
+/// Frontmatter field naming a page's loader module, relative to the page. Shorthand for
+/// `import { getStaticProps } from "..."`.
+pub const LOADER_FIELD: &str = "loader";
+
+/// Global the server entry exposes the page's `getStaticProps` through, so it can be run
+/// before the page is rendered.
+pub const LOADER_GLOBAL: &str = "__kaffeLoader";
+
+/// Global holding the `props` the loader returned, which the page's components are
+/// rendered with.
+pub const STATIC_PROPS_GLOBAL: &str = "__kaffeStaticProps";
+
+/// Global [`script`] records whether the loader has settled in, as a [`LoaderState`].
+pub const STATE_GLOBAL: &str = "__kaffeLoad";
+
+/// What a page's `getStaticProps` is called with.
+#[derive(Debug, Serialize)]
+pub struct LoaderContext<'a> {
+    /// The URL the page is served from.
+    pub url: &'a str,
+    pub frontmatter: &'a Frontmatter,
+    /// `"development"` or `"production"`.
+    pub mode: &'a str,
+}
+
+/// How a loader run by [`script`] went. It hasn't settled if it's still waiting on
+/// something once the runtime's event loop is empty.
+#[derive(Debug, Default, Deserialize)]
+pub struct LoaderState {
+    pub done: bool,
+    pub error: Option<String>,
+}
+
+/// Script calling the page's `getStaticProps` with `context`. Once it has settled the
+/// returned `props` are in [`STATIC_PROPS_GLOBAL`] and the outcome in [`STATE_GLOBAL`].
+pub fn script(context: &LoaderContext) -> String {
+    format!(
+        r#"globalThis.{state} = {{ done: false }};
+Promise.resolve()
+  .then(() => globalThis.{loader}({context}))
+  .then(
+    (result) => {{
+      const props = result && result.props;
+      if (props === null || typeof props !== "object" || Array.isArray(props)) {{
+        globalThis.{state}.error = "it must return an object like `{{ props: {{ ... }} }}`";
+      }} else {{
+        globalThis.{props} = props;
+      }}
+    }},
+    (error) => {{
+      globalThis.{state}.error = String((error && error.stack) || error);
+    }},
+  )
+  .finally(() => {{
+    globalThis.{state}.done = true;
+  }});
+"#,
+        state = STATE_GLOBAL,
+        loader = LOADER_GLOBAL,
+        props = STATIC_PROPS_GLOBAL,
+        context = serde_json::to_string(context).unwrap_or_else(|_| "{}".to_string()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calls_the_loader_with_the_context_as_json() {
+        let frontmatter =
+            Frontmatter::from([("title".to_string(), "\"Hi\" </script>".to_string())]);
+        let script = script(&LoaderContext {
+            url: "/blog/post.html",
+            frontmatter: &frontmatter,
+            mode: "production",
+        });
+        assert!(script.contains(
+            r#"globalThis.__kaffeLoader({"url":"/blog/post.html","frontmatter":{"title":"\"Hi\" </script>"},"mode":"production"})"#
+        ));
+        assert!(script.starts_with("globalThis.__kaffeLoad = { done: false };"));
+        assert!(script.contains("globalThis.__kaffeStaticProps = props;"));
+        assert!(script.contains("globalThis.__kaffeLoad.done = true;"));
+    }
+
+    #[test]
+    fn reads_how_the_loader_went() {
+        let state: LoaderState = serde_json::from_str(r#"{"done":false}"#).unwrap();
+        assert!(!state.done);
+        assert_eq!(state.error, None);
+
+        let state: LoaderState =
+            serde_json::from_str(r#"{"done":true,"error":"Error: offline"}"#).unwrap();
+        assert!(state.done);
+        assert_eq!(state.error.as_deref(), Some("Error: offline"));
+    }
+}
@@ -1,28 +1,33 @@
 use actix_files as fs;
-use actix_web::Result;
-use actix_web::{middleware, App, HttpServer};
-use clap::Parser;
-use std::fs::create_dir_all;
+use actix_web::dev::Service;
+use actix_web::{http::header, middleware, web, App, HttpServer};
+use clap::{Parser, Subcommand};
 use std::{fs as std_fs, io};
 use tokio::fs as tokio_fs;
-use tokio::io::ErrorKind;
 
 use walkdir::WalkDir;
 
+use std::collections::BTreeSet;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
 
-use anyhow::anyhow;
+use deno_core::futures::{stream, StreamExt};
 use deno_core::{error::AnyError, v8, JsRuntime};
-use std::{
-    fs::{read_to_string, File},
-    io::Write,
-    process::Command,
-    rc::Rc,
-};
-
-#[derive(Parser, Debug)]
+use std::{fs::File, io::Write, rc::Rc};
+
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Project root that every other path is resolved against
+    #[arg(short = 'r', long, default_value = ".")]
+    root: PathBuf,
+
     #[arg(short = 'i', long, default_value = "examples")]
     input_directory: PathBuf,
 
@@ -30,28 +35,134 @@ struct Args {
     server_port: String,
 
     #[arg(short = 'c', long, default_value = "client/src/components")]
-    client_component_directory: String,
-
-    #[arg(short = 'b', long, default_value = "client/dist/components")]
-    client_build_dir: String,
+    client_component_directory: PathBuf,
 
     #[arg(short = 'o', long, default_value = "output")]
     output_dir: PathBuf,
+
+    /// Render a Markdown element through a component, e.g. `h2=components/Heading`
+    #[arg(long = "component-map", value_parser = ComponentMapping::parse)]
+    component_map: Vec<ComponentMapping>,
+
+    /// Keep building the remaining pages after a failure and report every error at the end
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Keep each page's scratch build directory instead of deleting it, for debugging
+    #[arg(long)]
+    keep_temp: bool,
+
+    /// Rebuild every page, ignoring the build cache
+    #[arg(long)]
+    force: bool,
+
+    /// Number of pages to build at once, defaults to the number of CPUs
+    #[arg(short = 'j', long)]
+    jobs: Option<NonZeroUsize>,
+
+    /// Inline the CSS the top of each page needs into its `<head>` and load the full
+    /// stylesheets without blocking rendering
+    #[arg(long)]
+    critical_css: bool,
+
+    /// Build for local development, or minified and cacheable for production
+    #[arg(long, value_enum, default_value_t = Mode::Dev)]
+    mode: Mode,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Scaffold a new project with the default templates written out for customisation
+    Init {
+        #[arg(default_value = ".")]
+        path: PathBuf,
+    },
 }
 
-use kaffe::parser::{generate_html, parse_markdown};
+impl Args {
+    fn resolve_paths(&mut self, project: &Project) {
+        self.input_directory = project.path(&self.input_directory);
+        self.client_component_directory = project.path(&self.client_component_directory);
+        self.output_dir = project.path(&self.output_dir);
+    }
+
+    fn jobs(&self) -> usize {
+        self.jobs
+            .or_else(|| thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
+    }
+}
+
+use kaffe::bundler::{bundle, common_dir, BundleOptions, BundleTarget};
+use kaffe::cache::{self, BuildCache, PageInputs, PageRecord, RebuildReason};
+use kaffe::codegen::{generate_html, GeneratedPage, PROPS_GLOBAL, PROPS_SERIALIZER};
+use kaffe::config::Config;
+use kaffe::css;
+use kaffe::diagnostic::Diagnostic;
+use kaffe::images::ImageProcessor;
+use kaffe::imports::specifier_between;
+use kaffe::loader::{self, LoaderContext, LoaderState, STATE_GLOBAL};
+use kaffe::manifest::Manifest;
+use kaffe::mapping::{ComponentMap, ComponentMapping};
+use kaffe::mode::Mode;
+use kaffe::parallel;
+use kaffe::parser::{parse_document, Document};
+use kaffe::project::{Project, ProjectFile};
+use kaffe::public::{PublicFiles, PUBLIC_DIR};
+use kaffe::resolve::{import_file, resolve_imports, ImportResolver};
+use kaffe::sass::CompiledSass;
+use kaffe::scratch::{scratch_root, ScratchDir};
+use kaffe::sizes::{move_sourcemaps, BudgetConfig, PageFile, SizeReport, SOURCEMAP_DIR};
+use kaffe::template::{PageAssets, PageSummary, SiteContext, TemplateRenderer};
+use kaffe::validate::{validate_components, ComponentIndex};
+use kaffe::{Error, Result};
+
+/// Generated entry file names inside a page's scratch directory. Bundles are written next
+/// to them with a `.js` extension.
+const CLIENT_ENTRY: &str = "client-entry.tsx";
+const SERVER_ENTRY: &str = "server-entry.tsx";
+
+/// Stylesheet entries: one in the scratch root for the styles pages share, and one in
+/// each page's scratch directory for the rest.
+const COMMON_STYLES: &str = "common.css";
+const STYLES_ENTRY: &str = "styles.css";
+
+/// Packages every page loads, bundled into a shared chunk of their own.
+const VENDOR_MODULES: &[&str] = &["react", "react-dom/client"];
+const VENDOR_ENTRY: &str = "vendor.ts";
+
+/// `Cache-Control` for fingerprinted static files in production.
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let args = Args::parse();
+    let mut args = Args::parse();
 
-    // The generated server/client entrypoints will need the components to exist relative to
-    // the files, so we just copy them to the build dir
-    let _ = copy_files(&args.client_component_directory, &args.client_build_dir);
+    if let Some(Command::Init { path }) = &args.command {
+        return init(path);
+    }
 
-    if let Err(e) = run(&args).await {
-        eprintln!("Error during file generation: {:?}", e);
-        return Ok(());
+    let project = Project::new(&args.root);
+    args.resolve_paths(&project);
+
+    // Building blocks on bundling, rendering and the `--jobs` workers, so it runs beside
+    // the server's executor rather than on it
+    let build_args = args.clone();
+    let build_project = project.clone();
+    let built = tokio::task::spawn_blocking(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map(|runtime| runtime.block_on(run(&build_args, &build_project)))
+    })
+    .await
+    .unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()))?;
+    if let Err(errors) = built {
+        eprintln!("Error during file generation:");
+        for error in &errors {
+            eprintln!("  {}", error);
+        }
+        std::process::exit(1);
     }
 
     println!("Starting server...");
@@ -65,116 +176,174 @@ async fn main() -> std::io::Result<()> {
         port
     );
 
-    HttpServer::new(|| {
+    let output_dir = args.output_dir.clone();
+    let mode = args.mode;
+    // Only fingerprinted files can be kept for good, since the others keep their names
+    // when they change
+    let fingerprinted = Arc::new(if mode.is_dev() {
+        BTreeSet::new()
+    } else {
+        Manifest::load(&output_dir.join("static")).fingerprinted()
+    });
+    HttpServer::new(move || {
+        let mut static_files = fs::Files::new("/", output_dir.join("static"));
+        if mode.is_dev() {
+            static_files = static_files.show_files_listing();
+        }
+        let fingerprinted = fingerprinted.clone();
+        let static_scope = web::scope("/static")
+            .wrap_fn(move |request, service| {
+                let immutable = request
+                    .path()
+                    .strip_prefix("/static/")
+                    .is_some_and(|path| fingerprinted.contains(path));
+                let response = service.call(request);
+                async move {
+                    let mut response = response.await?;
+                    if immutable && response.status().is_success() {
+                        response.headers_mut().insert(
+                            header::CACHE_CONTROL,
+                            header::HeaderValue::from_static(IMMUTABLE_CACHE_CONTROL),
+                        );
+                    }
+                    Ok(response)
+                }
+            })
+            .service(static_files);
         App::new()
             .wrap(middleware::Compress::default())
-            .service(fs::Files::new("/static", "output/static").show_files_listing())
-            .service(fs::Files::new("/", "output").index_file("index.html"))
+            .service(static_scope)
+            .service(fs::Files::new("/", &output_dir).index_file("index.html"))
     })
     .listen(listener)?
     .run()
     .await
 }
 
-async fn bundle_react_component(
-    markdown_input: &str,
-    file_name: &str,
-) -> Result<String, anyhow::Error> {
-    let parsed_nodes = match parse_markdown(markdown_input) {
-        Ok(nodes) => nodes,
-        Err(e) => return Err(anyhow!("Failed to parse markdown: {:?}", e)),
-    };
+fn init(path: &Path) -> std::io::Result<()> {
+    let created = Project::new(path)
+        .init()
+        .map_err(|e| io::Error::other(e.to_string()))?;
 
-    let (html_content, imports, react_components) = generate_html(&parsed_nodes)
-        .await
-        .map_err(|e| anyhow!("Failed to generate HTML: {:?}", e))?;
+    for file in &created {
+        println!("Created {}", file.display());
+    }
+    println!(
+        "Project initialised in {}, install the client dependencies with `cd client && yarn`",
+        path.display()
+    );
+    Ok(())
+}
+
+/// Parses a page, pointing its links to files in `public/` at their copies.
+fn parse_page(markdown_input: &str, input_path: &Path, site: &Site) -> Result<Document> {
+    let mut document = parse_document(markdown_input).map_err(|e| Error::Parse {
+        file: input_path.to_path_buf(),
+        message: e.to_string(),
+    })?;
+    site.public.rewrite_links(&mut document.nodes);
+    Ok(document)
+}
 
-    let mut imports_string = imports.join("\n");
-    let mut components_string = react_components.join(", ");
+fn generate_entries(
+    document: &Document,
+    input_path: &Path,
+    input_dir: &Path,
+    scratch: &ScratchDir,
+    site: &Site,
+) -> Result<GeneratedPage> {
+    let project = &site.project;
+    let (images, diagnostics) = site.images.process_page(document, input_path, input_dir);
+    let mut page = generate_html(&document.nodes, &site.components, &images);
+    page.diagnostics.extend(diagnostics);
+    let (resolved, diagnostics) =
+        resolve_imports(document, input_path, &site.resolver, &site.component_index);
+    page.diagnostics.extend(diagnostics);
+    page.diagnostics.extend(validate_components(
+        document,
+        input_path,
+        &site.component_index,
+        &site.resolver,
+    ));
 
-    if react_components.len() == 0 {
-        components_string = "\"\"".to_string();
+    if page.diagnostics.iter().any(|d| d.is_error()) {
+        return Err(Error::Codegen {
+            file: input_path.to_path_buf(),
+            diagnostics: page.diagnostics,
+        });
+    }
+    for diagnostic in &page.diagnostics {
+        eprintln!("{}", diagnostic.report(&input_path.display().to_string()));
     }
 
-    if imports.len() == 0 {
-        imports_string = "".to_string();
+    // Local imports point at their files from the scratch directory the entries are in
+    let scratch_dir = scratch
+        .path()
+        .canonicalize()
+        .map_err(|e| Error::io(scratch.path(), e))?;
+    for import in &mut page.imports {
+        if let Some(file) = import_file(import, &resolved) {
+            let file = site.sass.replacement(file);
+            *import = import.with_source(specifier_between(&scratch_dir, file));
+        }
     }
 
-    let server_entry_content = load_file_contents("client/src/server-entry.template")?;
+    let imports_string = page.imports_js();
+    let components_string = page.components_js();
+    let html_content = &page.markup;
+
+    let server_entry_content = project
+        .template_for_page(
+            ProjectFile::ServerEntry,
+            input_path,
+            input_dir,
+            &page.frontmatter,
+        )?
+        .contents;
     let server_entry_content =
         server_entry_content.replace("%{{ REPLACE_IMPORTS }}%", &imports_string);
     let server_entry_content =
         server_entry_content.replace("%{{ REPLACE_COMPONENTS }}%", &components_string);
     let server_entry_content =
-        server_entry_content.replace("%{{ REPLACE_CONTENT }}%", &html_content);
-
-    let client_entry_content = load_file_contents("client/src/client-entry.template")?;
-    let client_entry_content =
-        client_entry_content.replace("%{{ REPLACE_IMPORTS }}%", &imports_string);
-    let client_entry_content =
-        client_entry_content.replace("%{{ REPLACE_COMPONENTS }}%", &components_string);
-    let client_entry_content =
-        client_entry_content.replace("%{{ REPLACE_CONTENT }}%", &html_content);
-
-    let output_dir = "client/dist";
+        server_entry_content.replace("%{{ REPLACE_CONTENT }}%", html_content);
 
-    create_dir_all(output_dir)
-        .map_err(|e| anyhow!("Failed to create directory at {}: {:?}", output_dir, e))?;
+    let client_entry_content = project
+        .template_for_page(
+            ProjectFile::ClientEntry,
+            input_path,
+            input_dir,
+            &page.frontmatter,
+        )?
+        .contents;
+    // A page without islands has nothing to hydrate, so it ships no JS
+    let client_entry_content = if page.islands.is_empty() {
+        "export {};\n".to_string()
+    } else {
+        client_entry_content
+            .replace("%{{ REPLACE_IMPORTS }}%", &page.client_imports_js())
+            .replace("%{{ REPLACE_ISLANDS }}%", &page.islands_js())
+    };
 
     // Write the server entry file
-    let server_file_path = format!("{}/server-entry.tsx", output_dir);
-    let mut server_file = File::create(&server_file_path)
-        .map_err(|e| anyhow!("Failed to create file at {}: {:?}", server_file_path, e))?;
+    let server_file_path = scratch.join(SERVER_ENTRY);
+    let mut server_file =
+        File::create(&server_file_path).map_err(|e| Error::io(&server_file_path, e))?;
     server_file
         .write_all(server_entry_content.as_bytes())
-        .map_err(|e| anyhow!("Failed to write to file at {}: {:?}", server_file_path, e))?;
+        .map_err(|e| Error::io(&server_file_path, e))?;
 
     // Write the client entry file
-    let client_file_path = format!("{}/client-entry.tsx", output_dir);
-    let mut client_file = File::create(&client_file_path)
-        .map_err(|e| anyhow!("Failed to create file at {}: {:?}", client_file_path, e))?;
+    let client_file_path = scratch.join(CLIENT_ENTRY);
+    let mut client_file =
+        File::create(&client_file_path).map_err(|e| Error::io(&client_file_path, e))?;
     client_file
         .write_all(client_entry_content.as_bytes())
-        .map_err(|e| anyhow!("Failed to write to file at {}: {:?}", client_file_path, e))?;
-
-    let status = Command::new("node")
-        .args(&["client/build.cjs", &format!("{}.js", &file_name)])
-        .output()
-        .map_err(|e| anyhow!("Failed to execute build script: {:?}", e))?;
-
-    if !status.status.success() {
-        let stderr = String::from_utf8_lossy(&status.stderr);
-        return Err(anyhow!("Build script failed: {}", stderr));
-    }
+        .map_err(|e| Error::io(&client_file_path, e))?;
 
-    Ok(html_content)
+    Ok(page)
 }
 
-fn copy_files(source_dir: &str, target_dir: &str) -> io::Result<()> {
-    std_fs::create_dir_all(target_dir)?;
-
-    for entry in std_fs::read_dir(source_dir)? {
-        let entry = entry?;
-        let file_name = entry.file_name();
-        let source_path = entry.path();
-        let target_path = Path::new(target_dir).join(file_name);
-
-        if source_path.is_dir() {
-            // Recursively copy the directory
-            copy_files(
-                &source_path.to_string_lossy(),
-                &target_path.to_string_lossy(),
-            )?;
-        } else if source_path.is_file() {
-            // Copy the file
-            std_fs::copy(&source_path, &target_path)?;
-        }
-    }
-
-    Ok(())
-}
-
-async fn run_js(js_runtime: &mut JsRuntime, file_path: &str) -> Result<usize, AnyError> {
+async fn run_js(js_runtime: &mut JsRuntime, file_path: &Path) -> Result<usize, AnyError> {
     let main_module = deno_core::resolve_path(file_path, &std::env::current_dir()?)?;
     let mod_id = js_runtime.load_main_es_module(&main_module).await?;
     let result = js_runtime.mod_evaluate(mod_id);
@@ -183,143 +352,1156 @@ async fn run_js(js_runtime: &mut JsRuntime, file_path: &str) -> Result<usize, An
     Ok(mod_id)
 }
 
-fn load_file_contents(file_path: &str) -> std::io::Result<String> {
-    read_to_string(file_path)
+fn retrieve_rendered_html(js_runtime: &mut JsRuntime) -> Result<String, AnyError> {
+    evaluate_to_string(js_runtime, "<render>", "renderToString();".to_string())
+}
+
+/// Runs the page's `getStaticProps` to completion, leaving the props it returned where
+/// the page's components are rendered from.
+async fn run_loader(
+    js_runtime: &mut JsRuntime,
+    context: &LoaderContext<'_>,
+) -> Result<LoaderState, AnyError> {
+    js_runtime.execute_script("<loader>", loader::script(context))?;
+    js_runtime.run_event_loop(Default::default()).await?;
+    let state = evaluate_to_string(
+        js_runtime,
+        "<loader>",
+        format!("JSON.stringify(globalThis.{});", STATE_GLOBAL),
+    )?;
+    Ok(serde_json::from_str(&state)?)
+}
+
+/// The props of the page's islands that couldn't be serialized, as `[message, help]`.
+fn retrieve_prop_problems(js_runtime: &mut JsRuntime) -> Result<Vec<(String, String)>, AnyError> {
+    let problems = evaluate_to_string(
+        js_runtime,
+        "<props>",
+        format!("JSON.stringify(globalThis.{}.problems);", PROPS_GLOBAL),
+    )?;
+    Ok(serde_json::from_str(&problems)?)
 }
 
-fn retrieve_rendered_html(js_runtime: &mut JsRuntime) -> Result<String> {
+fn evaluate_to_string(
+    js_runtime: &mut JsRuntime,
+    name: &'static str,
+    source: String,
+) -> Result<String, AnyError> {
+    let result = js_runtime.execute_script(name, source)?;
     let scope = &mut js_runtime.handle_scope();
-    let source = v8::String::new(scope, "renderToString();").unwrap();
-    let script = v8::Script::compile(scope, source, None).unwrap();
-    let result = script.run(scope).unwrap();
-    let result_str = result.to_string(scope).unwrap().to_rust_string_lossy(scope);
+    let result = v8::Local::new(scope, result);
+    let result_str = result
+        .to_string(scope)
+        .map(|s| s.to_rust_string_lossy(scope))
+        .unwrap_or_default();
     Ok(result_str)
 }
 
-async fn run(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
-    tokio_fs::create_dir_all(&args.output_dir).await?;
-    tokio_fs::create_dir_all(args.output_dir.join("static")).await?;
+/// Everything shared by the pages of a single build.
+struct Site {
+    project: Project,
+    config: Config,
+    renderer: TemplateRenderer,
+    components: ComponentMap,
+    component_index: ComponentIndex,
+    resolver: ImportResolver,
+    pages: Vec<PageSummary>,
+    /// Stylesheets loaded by more than one page, which go into `common.css`.
+    shared_styles: Vec<PathBuf>,
+    /// Sass reachable from any page, compiled before bundling.
+    sass: CompiledSass,
+    images: ImageProcessor,
+    /// Files copied from `public/`, which links and templates can refer to.
+    public: PublicFiles,
+}
+
+async fn run(args: &Args, project: &Project) -> Result<(), Vec<Error>> {
+    for dir in [args.output_dir.clone(), args.output_dir.join("static")] {
+        tokio_fs::create_dir_all(&dir)
+            .await
+            .map_err(|e| vec![Error::io(&dir, e)])?;
+    }
+
+    let page_paths = find_markdown_files(&args.input_directory);
+    let mut cache = if args.force {
+        BuildCache::default()
+    } else {
+        BuildCache::load(project)
+    };
+    cache.retain(&page_paths);
+
+    let options = options_hash(args);
+    let resolver =
+        ImportResolver::load(project, &args.client_component_directory).map_err(|e| vec![e])?;
+    let mappings = args
+        .component_map
+        .iter()
+        .map(|mapping| resolver.resolve_mapping(mapping))
+        .collect::<Result<_>>()
+        .map_err(|e| vec![e])?;
+    let config = Config::load(project).map_err(|e| vec![e])?;
+    let public =
+        PublicFiles::copy(project, &args.output_dir, &config.public).map_err(|e| vec![e])?;
+    let mut site = Site {
+        project: project.clone(),
+        images: ImageProcessor::new(
+            args.output_dir.join("static"),
+            project.path(PUBLIC_DIR),
+            config.images.clone(),
+        ),
+        public,
+        config,
+        renderer: TemplateRenderer::new(project),
+        components: ComponentMap::new(mappings),
+        component_index: ComponentIndex::scan(&args.client_component_directory),
+        resolver,
+        pages: Vec::new(),
+        shared_styles: Vec::new(),
+        sass: CompiledSass::default(),
+    };
+    let scanned = scan_pages(&page_paths, args, &site);
+    site.pages = scanned
+        .iter()
+        .filter_map(|page| page.summary.clone())
+        .collect();
+    site.shared_styles = css::shared_stylesheets(scanned.iter().map(|page| page.styles.as_slice()));
+    let modules: Vec<_> = scanned
+        .iter()
+        .flat_map(|page| page.modules.iter().cloned())
+        .collect();
+    site.sass = CompiledSass::compile(
+        project,
+        &args.client_component_directory,
+        &modules,
+        &site.resolver,
+    )
+    .map_err(|e| vec![e])?;
+    let shared_styles: Vec<_> = site
+        .shared_styles
+        .iter()
+        .map(|sheet| site.sass.stylesheet(sheet).to_path_buf())
+        .collect();
+    if let Some(page) = site
+        .pages
+        .iter()
+        .find(|page| site.public.contains(&page.url))
+    {
+        return Err(vec![Error::Config {
+            file: project.path(PUBLIC_DIR),
+            message: format!("{} is both a public file and a page", page.url),
+        }]);
+    }
+    let site_hash = cache::site_hash(project, &site.pages, &shared_styles, &site.public);
+
+    let mut stale = Vec::new();
+    for page in scanned {
+        let inputs = page_inputs(&page, &options, &site_hash, args, &site);
+        let reason = match (cache.get(&page.path), &inputs) {
+            _ if args.force => Some(RebuildReason::Forced),
+            (None, _) => Some(RebuildReason::NotCached),
+            (Some(_), None) => Some(RebuildReason::SourceChanged),
+            (Some(record), Some(inputs)) => record.stale(inputs),
+        };
+        if let Some(reason) = reason {
+            println!("Rebuilding {}: {}", page.path.display(), reason);
+            cache.remove(&page.path);
+            stale.push(StalePage {
+                path: page.path,
+                inputs,
+                document: page.document,
+                styles: page.styles,
+            });
+        }
+    }
+    println!(
+        "{} of {} pages unchanged",
+        page_paths.len() - stale.len(),
+        page_paths.len()
+    );
 
-    process_markdown_files(&args.input_directory, &args.output_dir, &args).await?;
+    let static_dir = args.output_dir.join("static");
+    let mut manifest = Manifest::load(&static_dir);
+    let page_keys: Vec<_> = page_paths
+        .iter()
+        .map(|path| page_key(path, &args.input_directory))
+        .collect();
+    manifest.retain(&page_keys);
+
+    let result = process_markdown_files(&stale, args, &site, &mut cache, &mut manifest).await;
+    let saved = cache.save(project).and_then(|_| manifest.save(&static_dir));
+    result?;
+    saved.map_err(|e| vec![e])?;
+    // Only once every page has been rebuilt, so no page written earlier still links them
+    manifest.prune(&static_dir).map_err(|e| vec![e])?;
+
+    // Production sourcemaps aren't linked from the bundles, and are only kept to measure
+    // them, so they're moved out of the served directory
+    let sourcemap_dir = if args.mode.is_dev() {
+        static_dir.clone()
+    } else {
+        let dir = project.cache_dir().join(SOURCEMAP_DIR);
+        move_sourcemaps(&static_dir, &dir).map_err(|e| vec![e])?;
+        dir
+    };
+    let report = report_sizes(&page_keys, &static_dir, &sourcemap_dir, &manifest, project)
+        .map_err(|e| vec![e])?;
+    println!("{}", report);
+    report.save(project).map_err(|e| vec![e])?;
+    // Dev bundles aren't minified, so they'd be held to budgets meant for what's deployed
+    let budgets = &site.config.budgets;
+    if !args.mode.is_dev() {
+        check_budgets(&report, budgets, &page_paths, &page_keys)?;
+    } else if budgets.js.is_some() || budgets.css.is_some() {
+        println!("Budgets are only checked with --mode prod, where bundles are minified");
+    }
 
     println!("Files generated successfully");
     Ok(())
 }
 
-fn filename_without_extension(path: &PathBuf) -> Option<String> {
-    path.file_name()
-        .and_then(|os_str| os_str.to_str())
-        .map(String::from)
+/// Fails every page whose client files are over the `[budgets]` in `kaffe.toml`.
+fn check_budgets(
+    report: &SizeReport,
+    budgets: &BudgetConfig,
+    page_paths: &[PathBuf],
+    page_keys: &[String],
+) -> Result<(), Vec<Error>> {
+    let over_budget: Vec<_> = report
+        .over_budget(budgets)
+        .into_iter()
+        .filter_map(|(key, message)| {
+            let path = page_paths
+                .iter()
+                .zip(page_keys)
+                .find(|(_, page_key)| *page_key == key)?
+                .0;
+            Some(Error::Budget {
+                file: path.clone(),
+                message,
+            })
+        })
+        .collect();
+    if over_budget.is_empty() {
+        Ok(())
+    } else {
+        Err(over_budget)
+    }
+}
+
+/// Measures the client files of every page in the manifest, finding the sourcemap of each
+/// in `sourcemap_dir` where esbuild wrote it in the static directory: beside the page's
+/// entry or stylesheet before it was fingerprinted, and beside each chunk.
+fn report_sizes(
+    page_keys: &[String],
+    static_dir: &Path,
+    sourcemap_dir: &Path,
+    manifest: &Manifest,
+    project: &Project,
+) -> Result<SizeReport> {
+    let mut report = SizeReport::default();
+    for key in page_keys {
+        let Some(chunks) = manifest.get(key) else {
+            continue;
+        };
+        let page_dir = sourcemap_dir.join(key);
+        let mut js = vec![PageFile {
+            path: static_dir.join(&chunks.entry),
+            sourcemap: page_dir.join(Path::new(CLIENT_ENTRY).with_extension("js.map")),
+        }];
+        js.extend(chunks.imports.iter().map(|chunk| PageFile {
+            path: static_dir.join(chunk),
+            sourcemap: sourcemap_dir.join(format!("{}.map", chunk)),
+        }));
+        let css: Vec<_> = chunks
+            .css
+            .iter()
+            .map(|sheet| {
+                let sourcemap = if manifest.asset(COMMON_STYLES) == Some(sheet.as_str()) {
+                    sourcemap_dir.join(format!("{}.map", COMMON_STYLES))
+                } else {
+                    page_dir.join(Path::new(STYLES_ENTRY).with_extension("css.map"))
+                };
+                PageFile {
+                    path: static_dir.join(sheet),
+                    sourcemap,
+                }
+            })
+            .collect();
+        report.add_page(project, static_dir, key.clone(), &js, &css)?;
+    }
+    Ok(report)
+}
+
+/// A page read from the input directory, before deciding whether it needs rebuilding.
+struct ScannedPage {
+    path: PathBuf,
+    source: Option<String>,
+    /// Kept so a page that has to be rebuilt isn't parsed again.
+    document: Option<Document>,
+    summary: Option<PageSummary>,
+    /// The local modules the page imports.
+    modules: Vec<PathBuf>,
+    /// Every stylesheet the page loads, in order.
+    styles: Vec<PathBuf>,
+}
+
+/// A page whose inputs changed since it was last built. `inputs` is missing when they
+/// couldn't be worked out, in which case the build reports why and nothing is cached.
+struct StalePage {
+    path: PathBuf,
+    inputs: Option<PageInputs>,
+    document: Option<Document>,
+    styles: Vec<PathBuf>,
+}
+
+/// Hashes the build options that change how pages are generated.
+fn options_hash(args: &Args) -> String {
+    cache::hash_bytes(format!(
+        "{}\n{}\n{}\n{:?}",
+        env!("CARGO_PKG_VERSION"),
+        args.mode,
+        args.critical_css,
+        args.component_map
+    ))
+}
+
+fn page_inputs(
+    page: &ScannedPage,
+    options: &str,
+    site_hash: &str,
+    args: &Args,
+    site: &Site,
+) -> Option<PageInputs> {
+    let summary = page.summary.as_ref()?;
+    let mut templates = String::new();
+    for file in [
+        ProjectFile::ServerEntry,
+        ProjectFile::ClientEntry,
+        ProjectFile::HtmlTemplate,
+    ] {
+        let template = site
+            .project
+            .template_for_page(
+                file,
+                &page.path,
+                &args.input_directory,
+                &summary.frontmatter,
+            )
+            .ok()?;
+        templates.push_str(&template.contents);
+    }
+    templates.push_str(&site.project.load(ProjectFile::UrlPolyfill).ok()?);
+
+    Some(PageInputs {
+        source: page.source.clone()?,
+        options: options.to_string(),
+        site: site_hash.to_string(),
+        templates: cache::hash_bytes(templates),
+    })
 }
 
 fn path_to_filename_without_extension(path: &Path) -> String {
-    path.file_name()
+    path.file_stem()
         .and_then(|os_str| os_str.to_str())
         .map(|s| s.to_string())
-        .unwrap_or_else(|| "".to_string())
+        .unwrap_or_default()
+}
+
+fn find_markdown_files(input_dir: &Path) -> Vec<PathBuf> {
+    WalkDir::new(input_dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|ext| ext == "md" || ext == "mdx")
+        })
+        .collect()
+}
+
+/// Identifies a page by its path relative to the input directory, which also names its
+/// scratch directory and client bundle.
+fn page_key(path: &Path, input_dir: &Path) -> String {
+    let relative_path = path.strip_prefix(input_dir).unwrap_or(path);
+    relative_path
+        .iter()
+        .map(|c| c.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn page_url(path: &Path, input_dir: &Path) -> String {
+    let relative_path = path.strip_prefix(input_dir).unwrap_or(path);
+    let components: Vec<_> = relative_path
+        .with_extension("html")
+        .iter()
+        .map(|c| c.to_string_lossy().into_owned())
+        .collect();
+    format!("/{}", components.join("/"))
+}
+
+/// Reads every page and collects what templates need to know about it up front, so any
+/// page can link to the others, along with the stylesheets it loads, so the ones pages
+/// share can be split out. Pages that fail to parse have no summary and are reported
+/// when they're built.
+fn scan_pages(paths: &[PathBuf], args: &Args, site: &Site) -> Vec<ScannedPage> {
+    parallel::map_ordered(
+        paths,
+        args.jobs(),
+        || (),
+        |_, path| {
+            let Ok(markdown_input) = std_fs::read_to_string(path) else {
+                return ScannedPage {
+                    path: path.clone(),
+                    source: None,
+                    document: None,
+                    summary: None,
+                    modules: Vec::new(),
+                    styles: Vec::new(),
+                };
+            };
+            let source = cache::hash_bytes(&markdown_input);
+            let Ok(document) = parse_page(&markdown_input, path, site) else {
+                return ScannedPage {
+                    path: path.clone(),
+                    source: Some(source),
+                    document: None,
+                    summary: None,
+                    modules: Vec::new(),
+                    styles: Vec::new(),
+                };
+            };
+
+            let page = generate_html(&document.nodes, &site.components, &Default::default());
+            let (resolved, _) =
+                resolve_imports(&document, path, &site.resolver, &site.component_index);
+            let modules: Vec<_> = page
+                .imports
+                .iter()
+                .filter_map(|import| import_file(import, &resolved))
+                .map(Path::to_path_buf)
+                .collect();
+
+            ScannedPage {
+                path: path.clone(),
+                source: Some(source),
+                summary: Some(summarize_page(path, &args.input_directory, &page)),
+                document: Some(document),
+                styles: css::stylesheets(&modules, &site.resolver),
+                modules,
+            }
+        },
+    )
+}
+
+fn summarize_page(path: &Path, input_dir: &Path, page: &GeneratedPage) -> PageSummary {
+    let filename = path_to_filename_without_extension(path);
+    PageSummary {
+        url: page_url(path, input_dir),
+        title: page.title().unwrap_or(&filename).to_string(),
+        frontmatter: page.frontmatter.clone(),
+        headings: page.headings.clone(),
+    }
+}
+
+/// A page whose entries have been generated, waiting to be bundled and rendered.
+struct PreparedPage {
+    path: PathBuf,
+    key: String,
+    inputs: Option<PageInputs>,
+    scratch: ScratchDir,
+    page: GeneratedPage,
+    /// Every stylesheet the page loads, in order.
+    styles: Vec<PathBuf>,
+}
+
+/// Reports each page as it finishes, in whatever order that happens.
+struct Progress {
+    total: usize,
+    done: AtomicUsize,
+}
+
+impl Progress {
+    fn new(total: usize) -> Self {
+        Self {
+            total,
+            done: AtomicUsize::new(0),
+        }
+    }
+
+    fn finish(&self, path: &Path, ok: bool) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        let status = if ok { "built" } else { "failed" };
+        println!("[{}/{}] {} {}", done, self.total, status, path.display());
+    }
 }
 
+/// Builds the pages in three stages, each running across `--jobs` workers: generating
+/// entries, bundling them and rendering them. Pages are written in whatever order they
+/// finish, but errors are always reported in page order. Every page that's written is
+/// recorded in `cache`.
 async fn process_markdown_files(
-    input_dir: &Path,
-    output_dir: &Path,
+    stale: &[StalePage],
     args: &Args,
-) -> Result<(), Box<dyn std::error::Error>> {
-    for entry in WalkDir::new(input_dir).into_iter().filter_map(|e| e.ok()) {
-        let path = entry.path().to_path_buf();
-        if path.is_file()
-            && path
-                .extension()
-                .map_or(false, |ext| ext == "md" || ext == "mdx")
-        {
-            let relative_path = path.strip_prefix(input_dir)?.to_path_buf();
-            let output_path = output_dir.join(&relative_path).with_extension("html");
-            if let Some(parent) = output_path.parent() {
-                std_fs::create_dir_all(parent)?;
+    site: &Site,
+    cache: &mut BuildCache,
+    manifest: &mut Manifest,
+) -> Result<(), Vec<Error>> {
+    if stale.is_empty() {
+        return Ok(());
+    }
+    let jobs = args.jobs();
+    let progress = Progress::new(stale.len());
+    println!("Building {} pages with {} jobs", stale.len(), jobs);
+
+    let pages_dir = scratch_root(&site.project);
+    std_fs::create_dir_all(&pages_dir).map_err(|e| vec![Error::io(&pages_dir, e)])?;
+    let polyfills = [site
+        .project
+        .materialize(ProjectFile::UrlPolyfill, &pages_dir)
+        .map_err(|e| vec![e])?];
+    let vendor_entry = pages_dir.join(VENDOR_ENTRY);
+    let vendor_source: String = VENDOR_MODULES
+        .iter()
+        .map(|module| format!("import \"{}\";\n", module))
+        .collect();
+    std_fs::write(&vendor_entry, vendor_source).map_err(|e| vec![Error::io(&vendor_entry, e)])?;
+    let static_dir = args.output_dir.join("static");
+    let bundle_options = BundleOptions::new(&site.project, args.mode);
+
+    let mut pages = parallel::map_ordered(
+        stale,
+        jobs,
+        || (),
+        |_, page| {
+            let result = prepare_page(page, args, site);
+            if result.is_err() {
+                progress.finish(&page.path, false);
             }
-            process_single_file(&path, &output_path).await?;
+            result
+        },
+    );
+    stop_early(&mut pages, args)?;
 
-            let filename_noext =
-                match filename_without_extension(&path.strip_prefix(input_dir)?.to_path_buf()) {
-                    Some(name) => name,
-                    None => "".to_string(),
-                };
+    let ready: Vec<_> = pages.iter().filter_map(|page| page.as_ref().ok()).collect();
+    let mut bundled = bundle_pages(
+        &ready,
+        &vendor_entry,
+        &polyfills,
+        &static_dir,
+        &bundle_options,
+        jobs,
+        &progress,
+    )
+    .await;
+    for (page, outcome) in ready.iter().zip(&mut bundled) {
+        if outcome.is_ok() {
+            let entry = static_dir
+                .join(&page.key)
+                .join(Path::new(CLIENT_ENTRY).with_extension("js"));
+            let name = Path::new(&page.key).with_extension("");
+            let published = manifest.add_page(
+                page.key.clone(),
+                &static_dir,
+                &entry,
+                &name.to_string_lossy(),
+            );
+            if let Err(e) = published {
+                progress.finish(&page.path, false);
+                *outcome = Err(e);
+            }
+        }
+    }
+    record(&mut pages, bundled);
+    stop_early(&mut pages, args)?;
 
-            let css_path = format!("client/dist/{}.css", &filename_noext);
-            // Check if the file exists
-            match tokio_fs::metadata(&css_path).await {
-                Ok(metadata) if metadata.is_file() => {
-                    // File exists and is a regular file, proceed to read it
-                    let client_css = tokio_fs::read_to_string(&css_path).await?;
-
-                    // Write the CSS content to the output directory
-                    tokio_fs::write(
-                        args.output_dir
-                            .join(format!("static/{}.css", &filename_noext)),
-                        client_css,
-                    )
-                    .await?;
-                }
-                Ok(_) => {
-                    eprintln!("The path exists but is not a file: {}", css_path);
-                }
-                Err(err) if err.kind() == ErrorKind::NotFound => {}
-                Err(err) => {
-                    eprintln!("An error occurred while checking the file: {}", err);
+    let ready: Vec<_> = pages.iter().filter_map(|page| page.as_ref().ok()).collect();
+    let styled = bundle_styles(&ready, site, &static_dir, &bundle_options, manifest).await;
+    for (page, outcome) in ready.iter().zip(&styled) {
+        if outcome.is_err() {
+            progress.finish(&page.path, false);
+        }
+    }
+    record(&mut pages, styled);
+    stop_early(&mut pages, args)?;
+
+    let manifest = &*manifest;
+    let context = SiteContext::new(&site.config, &site.pages, manifest, &site.public, args.mode);
+    let ready: Vec<_> = pages.iter().filter_map(|page| page.as_ref().ok()).collect();
+    let rendered = parallel::map_ordered(&ready, jobs, ssr_runtime, |runtime, page| {
+        let result = match runtime {
+            Ok(runtime) => runtime.block_on(render_page(page, args, site, manifest, &context)),
+            Err(e) => Err(Error::Ssr {
+                file: page.path.clone(),
+                message: format!("failed to start the SSR executor: {}", e),
+            }),
+        };
+        progress.finish(&page.path, result.is_ok());
+        result
+    });
+    for (page, outputs) in ready.iter().zip(&rendered) {
+        if let (Some(inputs), Ok(outputs)) = (&page.inputs, outputs) {
+            let mut dependencies = cache::dependencies(
+                &[
+                    page.scratch.join(CLIENT_ENTRY),
+                    page.scratch.join(SERVER_ENTRY),
+                ],
+                &site.resolver,
+            );
+            dependencies.extend(page.page.images.iter().filter_map(|image| {
+                Some((image.source.clone(), cache::hash_file(&image.source)?))
+            }));
+            cache.insert(
+                page.path.clone(),
+                PageRecord {
+                    inputs: inputs.clone(),
+                    dependencies,
+                    outputs: outputs.clone(),
+                    summary: summarize_page(&page.path, &args.input_directory, &page.page),
+                },
+            );
+        }
+    }
+    record(
+        &mut pages,
+        rendered.into_iter().map(|r| r.map(drop)).collect(),
+    );
+    stop_early(&mut pages, args)?;
+
+    let errors: Vec<_> = pages.into_iter().filter_map(Result::err).collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        eprintln!("{} of {} pages failed to build", errors.len(), stale.len());
+        Err(errors)
+    }
+}
+
+/// Fails the build with the first failed page, unless `--keep-going` was passed.
+fn stop_early(pages: &mut Vec<Result<PreparedPage>>, args: &Args) -> Result<(), Vec<Error>> {
+    if args.keep_going || pages.iter().all(Result::is_ok) {
+        return Ok(());
+    }
+    Err(std::mem::take(pages)
+        .into_iter()
+        .filter_map(Result::err)
+        .take(1)
+        .collect())
+}
+
+/// Stores the outcome of a stage, which only ran for the pages that hadn't failed yet.
+fn record(pages: &mut [Result<PreparedPage>], outcomes: Vec<Result<()>>) {
+    let pending = pages.iter_mut().filter(|page| page.is_ok());
+    for (page, outcome) in pending.zip(outcomes) {
+        if let Err(e) = outcome {
+            *page = Err(e);
+        }
+    }
+}
+
+fn prepare_page(stale: &StalePage, args: &Args, site: &Site) -> Result<PreparedPage> {
+    let input_path = &stale.path;
+    let project = &site.project;
+    // Pages that couldn't be read or parsed when they were scanned are tried again to
+    // report why
+    let parsed;
+    let document = match &stale.document {
+        Some(document) => document,
+        None => {
+            let markdown_input =
+                std_fs::read_to_string(input_path).map_err(|e| Error::io(input_path, e))?;
+            parsed = parse_page(&markdown_input, input_path, site)?;
+            &parsed
+        }
+    };
+    for sheet in &stale.styles {
+        if let Some(error) = site.sass.error(sheet) {
+            return Err(Error::Bundle {
+                file: input_path.to_path_buf(),
+                message: format!("failed to compile {}: {}", sheet.display(), error),
+            });
+        }
+    }
+    let key = page_key(input_path, &args.input_directory);
+    let scratch = ScratchDir::create(project, Path::new(&key), args.keep_temp)?;
+    if args.keep_temp {
+        eprintln!(
+            "Keeping build files for {} in {}",
+            input_path.display(),
+            scratch.path().display()
+        );
+    }
+
+    let page = generate_entries(document, input_path, &args.input_directory, &scratch, site)?;
+
+    Ok(PreparedPage {
+        path: input_path.to_path_buf(),
+        key,
+        inputs: stale.inputs.clone(),
+        scratch,
+        page,
+        styles: stale.styles.clone(),
+    })
+}
+
+/// Bundles the server side of every page, then the client side of the pages that made it
+/// that far.
+async fn bundle_pages(
+    pages: &[&PreparedPage],
+    vendor_entry: &Path,
+    polyfills: &[PathBuf],
+    static_dir: &Path,
+    options: &BundleOptions,
+    jobs: usize,
+    progress: &Progress,
+) -> Vec<Result<()>> {
+    let mut outcomes = bundle_servers(pages, polyfills, options, jobs).await;
+
+    let passed: Vec<_> = pages
+        .iter()
+        .zip(&outcomes)
+        .filter(|(_, outcome)| outcome.is_ok())
+        .map(|(page, _)| *page)
+        .collect();
+    let clients = bundle_clients(&passed, vendor_entry, static_dir, options, jobs).await;
+    let pending = outcomes.iter_mut().filter(|outcome| outcome.is_ok());
+    for (outcome, client) in pending.zip(clients) {
+        *outcome = client;
+    }
+
+    for (page, outcome) in pages.iter().zip(&outcomes) {
+        if outcome.is_err() {
+            progress.finish(&page.path, false);
+        }
+    }
+    outcomes
+}
+
+/// Bundles the stylesheets of every page into `static_dir` and records them in `manifest`:
+/// the ones pages share into [`COMMON_STYLES`], and the rest of each page's into a
+/// stylesheet named after the page. The shared stylesheet is linked first, so a page's
+/// own rules win over it.
+async fn bundle_styles(
+    pages: &[&PreparedPage],
+    site: &Site,
+    static_dir: &Path,
+    options: &BundleOptions,
+    manifest: &mut Manifest,
+) -> Vec<Result<()>> {
+    if pages.is_empty() {
+        return Vec::new();
+    }
+    // A failure outside any one page's files fails every page
+    let fail_all = |e: Error| -> Vec<Result<()>> {
+        pages
+            .iter()
+            .map(|page| {
+                Err(Error::Bundle {
+                    file: page.path.clone(),
+                    message: e.to_string(),
+                })
+            })
+            .collect()
+    };
+    let pages_dir = scratch_root(&site.project);
+    let (common_entry, page_entries) = match write_style_entries(pages, site, &pages_dir) {
+        Ok(entries) => entries,
+        Err(e) => return fail_all(e),
+    };
+
+    let entries: Vec<_> = std::iter::once(common_entry.clone())
+        .chain(page_entries.iter().flatten().cloned())
+        .collect();
+    match bundle(BundleTarget::Stylesheet, options, &entries, static_dir, &[]).await {
+        Ok(warnings) => {
+            for warning in warnings {
+                eprintln!("{}", warning);
+            }
+        }
+        Err(errors) => {
+            return pages
+                .iter()
+                .map(|page| Err(bundle_error(&page.path, &errors)))
+                .collect();
+        }
+    }
+
+    // esbuild mirrors the entries' layout below the scratch root into `static_dir`
+    let output = |entry: &Path| {
+        static_dir
+            .join(entry.strip_prefix(&pages_dir).unwrap_or(entry))
+            .with_extension("css")
+    };
+    let common = if site.shared_styles.is_empty() {
+        let file = output(&common_entry);
+        for leftover in [file.with_extension("css.map"), file] {
+            let _ = std_fs::remove_file(leftover);
+        }
+        None
+    } else {
+        match manifest.add_asset(static_dir, &output(&common_entry), COMMON_STYLES) {
+            Ok(common) => Some(common),
+            Err(e) => return fail_all(e),
+        }
+    };
+
+    pages
+        .iter()
+        .zip(&page_entries)
+        .map(|(page, entry)| {
+            let mut css = Vec::new();
+            if page
+                .styles
+                .iter()
+                .any(|sheet| site.shared_styles.contains(sheet))
+            {
+                css.extend(common.clone());
+            }
+            if let Some(entry) = entry {
+                let file = output(entry);
+                let name = Path::new(&page.key).with_extension("css");
+                css.push(manifest.add_asset(static_dir, &file, &name.to_string_lossy())?);
+                if let Some(dir) = file.parent() {
+                    let _ = std_fs::remove_dir(dir);
                 }
             }
+            manifest.set_stylesheets(&page.key, css);
+            Ok(())
+        })
+        .collect()
+}
+
+/// Writes the entry for the shared stylesheet into `pages_dir`, and one for each page
+/// with stylesheets of its own into its scratch directory, each `@import`ing its files.
+/// The shared entry is written even when there's nothing to share, which keeps
+/// `pages_dir` the root esbuild mirrors the entries from.
+fn write_style_entries(
+    pages: &[&PreparedPage],
+    site: &Site,
+    pages_dir: &Path,
+) -> Result<(PathBuf, Vec<Option<PathBuf>>)> {
+    let write = |dir: &Path, name: &str, sheets: &[PathBuf]| -> Result<PathBuf> {
+        let canonical = dir.canonicalize().map_err(|e| Error::io(dir, e))?;
+        let sheets: Vec<_> = sheets
+            .iter()
+            .map(|sheet| site.sass.stylesheet(sheet).to_path_buf())
+            .collect();
+        let entry = dir.join(name);
+        std_fs::write(&entry, css::import_sheet(&canonical, &sheets))
+            .map_err(|e| Error::io(&entry, e))?;
+        Ok(entry)
+    };
+
+    let common = write(pages_dir, COMMON_STYLES, &site.shared_styles)?;
+    let pages = pages
+        .iter()
+        .map(|page| {
+            let own: Vec<_> = page
+                .styles
+                .iter()
+                .filter(|sheet| !site.shared_styles.contains(sheet))
+                .cloned()
+                .collect();
+            if own.is_empty() {
+                Ok(None)
+            } else {
+                write(page.scratch.path(), STYLES_ENTRY, &own).map(Some)
+            }
+        })
+        .collect::<Result<_>>()?;
+    Ok((common, pages))
+}
+
+/// Server bundles stay one file per page, beside the page's entry, so SSR can load them on
+/// their own. Every page is bundled in one run; if that fails the pages are bundled
+/// separately instead so each error is attributed to the page that caused it.
+async fn bundle_servers(
+    pages: &[&PreparedPage],
+    polyfills: &[PathBuf],
+    options: &BundleOptions,
+    jobs: usize,
+) -> Vec<Result<()>> {
+    let entries: Vec<_> = pages
+        .iter()
+        .map(|page| page.scratch.join(SERVER_ENTRY))
+        .collect();
+    if pages.len() > 1 {
+        let outdir = common_dir(&entries);
+        if let Ok(warnings) =
+            bundle(BundleTarget::Server, options, &entries, &outdir, polyfills).await
+        {
+            for warning in warnings {
+                eprintln!("{}", warning);
+            }
+            return pages.iter().map(|_| Ok(())).collect();
         }
     }
-    Ok(())
+
+    stream::iter(pages.iter().zip(&entries))
+        .map(|(page, entry)| async move {
+            let entries = std::slice::from_ref(entry);
+            let warnings = bundle(
+                BundleTarget::Server,
+                options,
+                entries,
+                page.scratch.path(),
+                polyfills,
+            )
+            .await
+            .map_err(|errors| bundle_error(&page.path, &errors))?;
+            for warning in warnings {
+                eprintln!("{}", warning.report(&page.path.display().to_string()));
+            }
+            Ok(())
+        })
+        .buffered(jobs)
+        .collect()
+        .await
 }
 
-async fn process_single_file(
-    input_path: &Path,
-    output_path: &Path,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let markdown_input = tokio_fs::read_to_string(input_path).await?;
-    let filename = input_path
-        .file_name()
-        .and_then(|os_str| os_str.to_str())
-        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid filename"))?
-        .to_string();
+/// Bundles the client side of every page in one code split run into `static_dir`, so code
+/// shared between pages is downloaded once. The vendor entry imports [`VENDOR_MODULES`],
+/// which keeps them in a shared chunk even when a single page is rebuilt.
+///
+/// If the run fails, each page is bundled on its own to find the ones at fault, and the
+/// run is retried without them.
+async fn bundle_clients(
+    pages: &[&PreparedPage],
+    vendor_entry: &Path,
+    static_dir: &Path,
+    options: &BundleOptions,
+    jobs: usize,
+) -> Vec<Result<()>> {
+    if pages.is_empty() {
+        return Vec::new();
+    }
+    let entries = |pages: &[&PreparedPage]| -> Vec<PathBuf> {
+        std::iter::once(vendor_entry.to_path_buf())
+            .chain(pages.iter().map(|page| page.scratch.join(CLIENT_ENTRY)))
+            .collect()
+    };
 
-    let _ = bundle_react_component(
-        &markdown_input,
-        path_to_filename_without_extension(&input_path).as_str(),
+    let errors = match bundle(
+        BundleTarget::Client,
+        options,
+        &entries(pages),
+        static_dir,
+        &[],
     )
-    .await?;
+    .await
+    {
+        Ok(warnings) => {
+            for warning in warnings {
+                eprintln!("{}", warning);
+            }
+            return pages.iter().map(|_| Ok(())).collect();
+        }
+        Err(errors) => errors,
+    };
+
+    let mut outcomes: Vec<Result<()>> = stream::iter(pages)
+        .map(|page| async move {
+            let entries = [page.scratch.join(CLIENT_ENTRY)];
+            let outdir = page.scratch.join("client-check");
+            bundle(BundleTarget::Client, options, &entries, &outdir, &[])
+                .await
+                .map(drop)
+                .map_err(|errors| bundle_error(&page.path, &errors))
+        })
+        .buffered(jobs)
+        .collect()
+        .await;
+
+    let passed: Vec<_> = pages
+        .iter()
+        .zip(&outcomes)
+        .filter(|(_, outcome)| outcome.is_ok())
+        .map(|(page, _)| *page)
+        .collect();
+    // When no page fails on its own, the error comes from bundling them together
+    let retry = if passed.len() == pages.len() {
+        Err(errors)
+    } else if passed.is_empty() {
+        Ok(Vec::new())
+    } else {
+        bundle(
+            BundleTarget::Client,
+            options,
+            &entries(&passed),
+            static_dir,
+            &[],
+        )
+        .await
+    };
+
+    match retry {
+        Ok(warnings) => {
+            for warning in warnings {
+                eprintln!("{}", warning);
+            }
+        }
+        Err(errors) => {
+            for (page, outcome) in pages.iter().zip(&mut outcomes) {
+                if outcome.is_ok() {
+                    *outcome = Err(bundle_error(&page.path, &errors));
+                }
+            }
+        }
+    }
+    outcomes
+}
+
+fn bundle_error(file: &Path, errors: &[Diagnostic]) -> Error {
+    Error::Bundle {
+        file: file.to_path_buf(),
+        message: errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n  "),
+    }
+}
+
+/// `JsRuntime` can't move between threads, so every SSR worker drives its own runtimes
+/// on a single-threaded executor.
+fn ssr_runtime() -> std::io::Result<tokio::runtime::Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+}
 
+/// Renders a bundled page and writes it out, returning every file written.
+async fn render_page(
+    prepared: &PreparedPage,
+    args: &Args,
+    site: &Site,
+    manifest: &Manifest,
+    context: &SiteContext<'_>,
+) -> Result<Vec<PathBuf>> {
+    let input_path = &prepared.path;
+    let relative_path = input_path
+        .strip_prefix(&args.input_directory)
+        .unwrap_or(input_path);
+    let output_path = args.output_dir.join(relative_path).with_extension("html");
+    if let Some(parent) = output_path.parent() {
+        std_fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+    }
+
+    // Each page gets a fresh isolate so globals set by one page can't leak into another
     let mut js_runtime = JsRuntime::new(deno_core::RuntimeOptions {
         module_loader: Some(Rc::new(deno_core::FsModuleLoader)),
         ..Default::default()
     });
 
-    run_js(
-        &mut js_runtime,
-        format!(
-            "client/dist/ssr-{}.js",
-            path_to_filename_without_extension(&input_path)
+    let ssr_error = |e: AnyError| Error::Ssr {
+        file: input_path.to_path_buf(),
+        message: e.to_string(),
+    };
+    // Bundled code has NODE_ENV inlined, but packages may still look for `process`
+    js_runtime
+        .execute_script(
+            "<process>",
+            format!(
+                "globalThis.process = {{ env: {{ NODE_ENV: {:?} }} }};",
+                args.mode.node_env()
+            ),
         )
-        .as_str(),
-    )
-    .await?;
+        .map_err(ssr_error)?;
+    js_runtime
+        .execute_script("<props>", PROPS_SERIALIZER)
+        .map_err(ssr_error)?;
+    run_js(&mut js_runtime, &prepared.scratch.join("server-entry.js"))
+        .await
+        .map_err(ssr_error)?;
+    if let Some(module) = &prepared.page.loader {
+        let url = page_url(input_path, &args.input_directory);
+        let context = LoaderContext {
+            url: &url,
+            frontmatter: &prepared.page.frontmatter,
+            mode: args.mode.node_env(),
+        };
+        let state = run_loader(&mut js_runtime, &context)
+            .await
+            .map_err(ssr_error)?;
+        let message = match state {
+            LoaderState { done: false, .. } => Some(
+                "it never settled, and only code that runs without Node or browser APIs \
+                 can finish during the build"
+                    .to_string(),
+            ),
+            LoaderState { error, .. } => error,
+        };
+        if let Some(message) = message {
+            return Err(Error::Loader {
+                file: input_path.to_path_buf(),
+                message: format!("{}: {}", module, message),
+            });
+        }
+    }
 
-    let rendered_html = retrieve_rendered_html(&mut js_runtime)?;
-    let template = tokio_fs::read_to_string("client/template.html").await?;
+    let rendered_html = retrieve_rendered_html(&mut js_runtime).map_err(ssr_error)?;
+    let problems = retrieve_prop_problems(&mut js_runtime).map_err(ssr_error)?;
+    if !problems.is_empty() {
+        return Err(Error::Codegen {
+            file: input_path.to_path_buf(),
+            diagnostics: problems
+                .into_iter()
+                .map(|(message, help)| Diagnostic::error(message).with_help(help))
+                .collect(),
+        });
+    }
+    let template = site.project.template_for_page(
+        ProjectFile::HtmlTemplate,
+        input_path,
+        &args.input_directory,
+        &prepared.page.frontmatter,
+    )?;
 
-    let final_html = template
-        .replace("{{SSR_CONTENT}}", &rendered_html)
-        .replace(
-            "{{CLIENT_BUNDLE_PATH}}",
-            format!("{}.js", path_to_filename_without_extension(&input_path)).as_str(),
-        )
-        .replace(
-            "{{CLIENT_CSS_PATH}}",
-            format!("{}.css", path_to_filename_without_extension(&input_path)).as_str(),
-        )
-        .replace("{{TITLE}}", &filename);
+    let chunks = manifest.get(&prepared.key).ok_or_else(|| Error::Bundle {
+        file: input_path.to_path_buf(),
+        message: "no client bundle was recorded for the page".to_string(),
+    })?;
+    let static_dir = args.output_dir.join("static");
+    let critical_css = if args.critical_css {
+        let mut stylesheets = String::new();
+        for stylesheet in &chunks.css {
+            let path = static_dir.join(stylesheet);
+            let contents = tokio_fs::read_to_string(&path)
+                .await
+                .map_err(|e| Error::io(&path, e))?;
+            stylesheets.push_str(&contents);
+        }
+        css::critical_css(&stylesheets, &rendered_html)
+    } else {
+        String::new()
+    };
+    let assets = PageAssets {
+        js: chunks.entry.clone(),
+        css: chunks.css.clone(),
+        preload: chunks.imports.clone(),
+        critical_css,
+    };
+    let final_html = site.renderer.render(
+        &template,
+        &summarize_page(input_path, &args.input_directory, &prepared.page),
+        &rendered_html,
+        &assets,
+        context,
+    )?;
 
-    tokio_fs::write(output_path, final_html).await?;
+    tokio_fs::write(&output_path, final_html)
+        .await
+        .map_err(|e| Error::io(&output_path, e))?;
 
-    Ok(())
+    let assets = std::iter::once(&assets.js)
+        .chain(&assets.preload)
+        .chain(&assets.css);
+    let images = prepared.page.images.iter().flat_map(|image| &image.files);
+    Ok(std::iter::once(output_path)
+        .chain(assets.map(|asset| static_dir.join(asset)))
+        .chain(images.cloned())
+        .collect())
 }
@@ -0,0 +1,365 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::cache::hash_bytes;
+use crate::error::{Error, Result};
+use crate::imports::{imports_of, relative_imports, specifier_between};
+
+/// File in the static output directory mapping logical asset names to their fingerprinted
+/// files, and listing the client files each page loads.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Hex digits of the content hash put in fingerprinted file names.
+const FINGERPRINT_LENGTH: usize = 8;
+
+/// The client files one page loads, as fingerprinted paths relative to the static
+/// directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PageChunks {
+    /// The page's own entry, loaded with `<script type="module">`.
+    pub entry: String,
+    /// Shared chunks the entry imports, directly or through other chunks, which are
+    /// preloaded so the browser doesn't discover them one import at a time.
+    pub imports: Vec<String>,
+    /// The shared stylesheet if the page uses it, then the page's own.
+    pub css: Vec<String>,
+}
+
+/// Assets written to the static directory. Every file is fingerprinted with a hash of
+/// its contents, so it can be cached forever and a changed file always gets a new name.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Fingerprinted file for each logical name, like `blog/post1.js` to
+    /// `blog/post1.3fa9c2d1.js`.
+    assets: BTreeMap<String, String>,
+    /// Client files for every page, keyed by the page's path relative to the input
+    /// directory.
+    pages: BTreeMap<String, PageChunks>,
+}
+
+impl Manifest {
+    /// Loads the manifest written by the last build, or an empty one if there isn't one.
+    pub fn load(static_dir: &Path) -> Self {
+        fs::read_to_string(static_dir.join(MANIFEST_FILE))
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, static_dir: &Path) -> Result<()> {
+        let path = static_dir.join(MANIFEST_FILE);
+        let contents =
+            serde_json::to_string_pretty(self).map_err(|e| Error::io(&path, e.into()))?;
+        fs::write(&path, contents).map_err(|e| Error::io(&path, e))
+    }
+
+    pub fn get(&self, page: &str) -> Option<&PageChunks> {
+        self.pages.get(page)
+    }
+
+    /// The fingerprinted file for a logical asset name, relative to the static directory.
+    pub fn asset(&self, name: &str) -> Option<&str> {
+        self.assets.get(name).map(String::as_str)
+    }
+
+    pub fn assets(&self) -> &BTreeMap<String, String> {
+        &self.assets
+    }
+
+    /// Every file in the static directory whose name changes with its contents: the
+    /// published assets, and the shared chunks pages import, which esbuild names after
+    /// a hash of their contents.
+    pub fn fingerprinted(&self) -> BTreeSet<String> {
+        self.assets
+            .values()
+            .chain(self.pages.values().flat_map(|chunks| &chunks.imports))
+            .cloned()
+            .collect()
+    }
+
+    /// Publishes the client bundle of `page`, which esbuild wrote to `entry` inside
+    /// `static_dir`. The bundle is fingerprinted and moved to the logical `name` (without
+    /// extension), and the chunks it imports are recorded. Stylesheets are bundled on
+    /// their own, so the one esbuild wrote beside the bundle is discarded.
+    pub fn add_page(
+        &mut self,
+        page: String,
+        static_dir: &Path,
+        entry: &Path,
+        name: &str,
+    ) -> Result<()> {
+        let canonical = static_dir
+            .canonicalize()
+            .map_err(|e| Error::io(static_dir, e))?;
+        let imports = chunks_of(entry)
+            .iter()
+            .map(|path| relative(&canonical, path))
+            .collect();
+        let js = self.add_asset(static_dir, entry, &format!("{}.js", name))?;
+
+        let css_file = entry.with_extension("css");
+        for leftover in [css_file.with_extension("css.map"), css_file] {
+            if leftover.is_file() {
+                fs::remove_file(&leftover).map_err(|e| Error::io(&leftover, e))?;
+            }
+        }
+
+        // esbuild mirrors the scratch layout, leaving a directory per page behind, which
+        // is kept while it still holds sourcemaps
+        if let Some(dir) = entry.parent() {
+            let _ = fs::remove_dir(dir);
+        }
+
+        self.pages.insert(
+            page,
+            PageChunks {
+                entry: js,
+                imports,
+                css: Vec::new(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Fingerprints `file`, written by esbuild inside `static_dir`, and moves it to the
+    /// `logical` path. Returns the fingerprinted path relative to `static_dir`.
+    pub fn add_asset(&mut self, static_dir: &Path, file: &Path, logical: &str) -> Result<String> {
+        let static_dir = static_dir
+            .canonicalize()
+            .map_err(|e| Error::io(static_dir, e))?;
+        let target =
+            fingerprint(file, &static_dir.join(logical)).map_err(|e| Error::io(file, e))?;
+        let fingerprinted = relative(&static_dir, &target);
+        self.assets
+            .insert(logical.to_string(), fingerprinted.clone());
+        Ok(fingerprinted)
+    }
+
+    /// Records the stylesheets `page` links, as published by [`Manifest::add_asset`].
+    pub fn set_stylesheets(&mut self, page: &str, css: Vec<String>) {
+        if let Some(chunks) = self.pages.get_mut(page) {
+            chunks.css = css;
+        }
+    }
+
+    /// Drops the pages that no longer exist, along with their assets.
+    pub fn retain(&mut self, pages: &[String]) {
+        self.pages.retain(|page, _| pages.contains(page));
+        let published: BTreeSet<_> = self
+            .pages
+            .values()
+            .flat_map(|chunks| std::iter::once(&chunks.entry).chain(&chunks.css))
+            .cloned()
+            .collect();
+        self.assets.retain(|_, file| published.contains(file));
+    }
+
+    /// Deletes the fingerprinted files and chunks in `static_dir` the manifest no longer
+    /// lists, left behind by pages that were removed or rebuilt with new contents, along
+    /// with their sourcemaps.
+    pub fn prune(&self, static_dir: &Path) -> Result<()> {
+        let fingerprinted = self.fingerprinted();
+        for entry in WalkDir::new(static_dir).into_iter().filter_map(|e| e.ok()) {
+            let path = entry.path();
+            let file = relative(static_dir, path);
+            if !entry.file_type().is_file()
+                || !is_fingerprinted(path)
+                || fingerprinted.contains(&file)
+            {
+                continue;
+            }
+            fs::remove_file(path).map_err(|e| Error::io(path, e))?;
+            let map = path.with_file_name(format!("{}.map", entry.file_name().to_string_lossy()));
+            if map.is_file() {
+                fs::remove_file(&map).map_err(|e| Error::io(&map, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `path` is a bundle or stylesheet named after its contents: one fingerprinted
+/// by [`fingerprint`], or a chunk esbuild split out.
+fn is_fingerprinted(path: &Path) -> bool {
+    if !path
+        .extension()
+        .is_some_and(|ext| ext == "js" || ext == "css")
+    {
+        return false;
+    }
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    if stem.starts_with("chunk-") {
+        return true;
+    }
+    stem.rsplit_once('.').is_some_and(|(_, hash)| {
+        hash.len() == FINGERPRINT_LENGTH && hash.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+/// Moves `file` to `logical` with a hash of its contents inserted before the extension,
+/// rewriting its relative imports for the new location. Returns the new path.
+fn fingerprint(file: &Path, logical: &Path) -> io::Result<PathBuf> {
+    let contents = fs::read_to_string(file)?;
+    let dir = logical.parent().unwrap_or(Path::new(""));
+    fs::create_dir_all(dir)?;
+    let dir = dir.canonicalize()?;
+
+    let mut rewritten = contents.clone();
+    for (specifier, target) in relative_imports(file, &contents) {
+        let moved = specifier_between(&dir, &target);
+        for quote in ['"', '\''] {
+            rewritten = rewritten.replace(
+                &format!("{quote}{specifier}{quote}"),
+                &format!("{quote}{moved}{quote}"),
+            );
+        }
+    }
+
+    // Development bundles link a sourcemap, which stays where esbuild wrote it since the
+    // sources it lists are relative to it
+    let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+    let map = file.with_file_name(format!("{}.map", file_name));
+    if map.is_file() {
+        let moved = specifier_between(&dir, &map.canonicalize()?);
+        rewritten = rewritten.replace(
+            &format!("sourceMappingURL={}.map", file_name),
+            &format!("sourceMappingURL={}", moved),
+        );
+    }
+
+    let hash = hash_bytes(&rewritten);
+    let stem = logical.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = logical.extension().unwrap_or_default().to_string_lossy();
+    let target = dir.join(format!(
+        "{}.{}.{}",
+        stem,
+        &hash[..FINGERPRINT_LENGTH],
+        extension
+    ));
+    fs::write(&target, rewritten)?;
+    fs::remove_file(file)?;
+    Ok(target)
+}
+
+/// `path` relative to `static_dir`, with `/` separators.
+fn relative(static_dir: &Path, path: &Path) -> String {
+    path.strip_prefix(static_dir)
+        .unwrap_or(path)
+        .iter()
+        .map(|c| c.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn chunks_of(entry: &Path) -> BTreeSet<PathBuf> {
+    let mut pending = vec![entry.to_path_buf()];
+    let mut chunks = BTreeSet::new();
+
+    while let Some(path) = pending.pop() {
+        let Ok(contents) = fs::read_to_string(&path) else {
+            continue;
+        };
+        for import in imports_of(&path, &contents) {
+            if import.extension().is_some_and(|ext| ext == "js") && chunks.insert(import.clone()) {
+                pending.push(import);
+            }
+        }
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprinted_files_are_assets_and_chunks() {
+        let mut manifest = Manifest::default();
+        manifest
+            .assets
+            .insert("blog/post1.js".into(), "blog/post1.3fa9c2d1.js".into());
+        manifest.pages.insert(
+            "blog/post1.mdx".into(),
+            PageChunks {
+                entry: "blog/post1.3fa9c2d1.js".into(),
+                imports: vec!["chunk-ABCD1234.js".into()],
+                ..Default::default()
+            },
+        );
+
+        let fingerprinted = manifest.fingerprinted();
+        assert!(fingerprinted.contains("blog/post1.3fa9c2d1.js"));
+        assert!(fingerprinted.contains("chunk-ABCD1234.js"));
+        assert!(!fingerprinted.contains(MANIFEST_FILE));
+        assert!(!fingerprinted.contains("vendor.js"));
+        assert_eq!(fingerprinted.len(), 2);
+    }
+
+    #[test]
+    fn recognises_fingerprinted_files() {
+        assert!(is_fingerprinted(Path::new("blog/post1.3fa9c2d1.js")));
+        assert!(is_fingerprinted(Path::new("blog/post1.5f546eb4.css")));
+        assert!(is_fingerprinted(Path::new("chunk-ABCD1234.js")));
+        assert!(!is_fingerprinted(Path::new("vendor.js")));
+        assert!(!is_fingerprinted(Path::new("manifest.json")));
+        assert!(!is_fingerprinted(Path::new("blog/post1.3fa9c2d1.js.map")));
+        assert!(!is_fingerprinted(Path::new("images/photo.3fa9c2d1.webp")));
+        assert!(!is_fingerprinted(Path::new("jquery.min.js")));
+    }
+
+    #[test]
+    fn prunes_files_the_manifest_no_longer_lists() {
+        let dir = tempfile::tempdir().unwrap();
+        let static_dir = dir.path();
+        fs::create_dir_all(static_dir.join("blog")).unwrap();
+        for file in [
+            "blog/post1.3fa9c2d1.js",
+            "blog/post1.0badc0de.js",
+            "blog/post1.0badc0de.js.map",
+            "blog/post2.12345678.css",
+            "chunk-ABCD1234.js",
+            "chunk-DEADBEEF.js",
+            "vendor.js",
+            MANIFEST_FILE,
+        ] {
+            fs::write(static_dir.join(file), "").unwrap();
+        }
+
+        let mut manifest = Manifest::default();
+        manifest
+            .assets
+            .insert("blog/post1.js".into(), "blog/post1.3fa9c2d1.js".into());
+        manifest.pages.insert(
+            "blog/post1.mdx".into(),
+            PageChunks {
+                entry: "blog/post1.3fa9c2d1.js".into(),
+                imports: vec!["chunk-ABCD1234.js".into()],
+                ..Default::default()
+            },
+        );
+        manifest.prune(static_dir).unwrap();
+
+        for kept in [
+            "blog/post1.3fa9c2d1.js",
+            "chunk-ABCD1234.js",
+            "vendor.js",
+            MANIFEST_FILE,
+        ] {
+            assert!(static_dir.join(kept).is_file(), "{} was deleted", kept);
+        }
+        for pruned in [
+            "blog/post1.0badc0de.js",
+            "blog/post1.0badc0de.js.map",
+            "blog/post2.12345678.css",
+            "chunk-DEADBEEF.js",
+        ] {
+            assert!(!static_dir.join(pruned).exists(), "{} was kept", pruned);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use anyhow::anyhow;
+
+use crate::parser::Rendering;
+
+/// A single `element=path` rule, e.g. `h2=components/Heading`.
+#[derive(Debug, Clone)]
+pub struct ComponentMapping {
+    pub element: String,
+    pub component: String,
+    pub path: String,
+}
+
+impl ComponentMapping {
+    pub fn parse(spec: &str) -> Result<Self, anyhow::Error> {
+        let (element, path) = spec.split_once('=').ok_or_else(|| {
+            anyhow!(
+                "Invalid component mapping `{}`, expected element=path",
+                spec
+            )
+        })?;
+
+        let element = element.trim();
+        let path = path
+            .trim()
+            .trim_end_matches(".tsx")
+            .trim_end_matches(".jsx");
+
+        if !MAPPABLE_ELEMENTS.contains(&element) {
+            return Err(anyhow!(
+                "Cannot map `{}`, supported elements are: {}",
+                element,
+                MAPPABLE_ELEMENTS.join(", ")
+            ));
+        }
+
+        let (component, rendering) =
+            Rendering::of_name(path.rsplit('/').next().unwrap_or_default());
+        let component = component.to_string();
+        // Mapped elements are rendered on the server only, which `.server` just restates
+        if rendering == Rendering::Client {
+            return Err(anyhow!(
+                "Cannot map `{}` to the client-only `{}`, mapped elements are rendered on the server",
+                element,
+                path
+            ));
+        }
+        if !component.starts_with(|c: char| c.is_ascii_uppercase()) {
+            return Err(anyhow!(
+                "Component `{}` mapped to `{}` must start with an uppercase letter",
+                component,
+                element
+            ));
+        }
+
+        // Bare paths are relative to the parent of the component directory, so the default
+        // `components/Heading` finds `client/src/components/Heading.tsx`
+        let path = if path.starts_with('.') || path.starts_with('/') || path.starts_with('@') {
+            path.to_string()
+        } else {
+            format!("./{}", path)
+        };
+
+        Ok(Self {
+            element: element.to_string(),
+            component,
+            path,
+        })
+    }
+}
+
+/// Elements emitted by `generate_html` that can be rendered through a component.
+pub const MAPPABLE_ELEMENTS: &[&str] = &[
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "p",
+    "a",
+    "img",
+    "pre",
+    "code",
+    "ul",
+    "li",
+    "blockquote",
+    "strong",
+    "em",
+];
+
+/// The MDXProvider equivalent: every mapped element is rendered through its component
+/// instead of the plain HTML tag.
+#[derive(Debug, Clone, Default)]
+pub struct ComponentMap {
+    mappings: Vec<ComponentMapping>,
+}
+
+impl ComponentMap {
+    pub fn new(mappings: Vec<ComponentMapping>) -> Self {
+        let mut map = Self::default();
+        for mapping in mappings {
+            map.insert(mapping);
+        }
+        map
+    }
+
+    /// Adds a mapping, replacing any earlier one for the same element.
+    pub fn insert(&mut self, mapping: ComponentMapping) {
+        self.mappings.retain(|m| m.element != mapping.element);
+        self.mappings.push(mapping);
+    }
+
+    pub fn get(&self, element: &str) -> Option<&ComponentMapping> {
+        self.mappings.iter().find(|m| m.element == element)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bare_paths_relative_to_the_component_directory() {
+        let mapping = ComponentMapping::parse(" h2 = components/Heading.tsx ").unwrap();
+        assert_eq!(mapping.element, "h2");
+        assert_eq!(mapping.component, "Heading");
+        assert_eq!(mapping.path, "./components/Heading");
+    }
+
+    #[test]
+    fn keeps_relative_absolute_and_aliased_paths() {
+        for path in [
+            "./Heading",
+            "../lib/Heading",
+            "/src/Heading",
+            "@/components/Heading",
+        ] {
+            let mapping = ComponentMapping::parse(&format!("h1={}.jsx", path)).unwrap();
+            assert_eq!(mapping.path, path);
+            assert_eq!(mapping.component, "Heading");
+        }
+    }
+
+    #[test]
+    fn names_server_components_without_their_suffix() {
+        let mapping = ComponentMapping::parse("pre=components/Code.server.tsx").unwrap();
+        assert_eq!(mapping.component, "Code");
+        assert_eq!(mapping.path, "./components/Code.server");
+    }
+
+    #[test]
+    fn rejects_invalid_mappings() {
+        for spec in [
+            "h2",
+            "div=components/Box",
+            "pre=components/Code.client",
+            "h2=components/heading",
+        ] {
+            assert!(ComponentMapping::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn replaces_earlier_mappings_for_an_element() {
+        let map = ComponentMap::new(vec![
+            ComponentMapping::parse("h2=components/Heading").unwrap(),
+            ComponentMapping::parse("h2=components/Title").unwrap(),
+        ]);
+        assert_eq!(map.get("h2").unwrap().component, "Title");
+        assert!(map.get("h3").is_none());
+    }
+}
@@ -0,0 +1,42 @@
+use std::fmt;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+/// Whether a site is built for local development or to be deployed. Every stage takes it
+/// from here so bundles, SSR and templates always agree.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// React's development build with its warnings, unminified client bundles with
+    /// sourcemaps, and static files served uncached with directory listings.
+    #[default]
+    Dev,
+    /// React's production build, minified client bundles with unlinked sourcemaps, and
+    /// fingerprinted static files served with long-lived cache headers.
+    Prod,
+}
+
+impl Mode {
+    /// The value of `process.env.NODE_ENV`, which picks React's development or production
+    /// build.
+    pub fn node_env(&self) -> &'static str {
+        match self {
+            Mode::Dev => "development",
+            Mode::Prod => "production",
+        }
+    }
+
+    pub fn is_dev(&self) -> bool {
+        *self == Mode::Dev
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Dev => write!(f, "dev"),
+            Mode::Prod => write!(f, "prod"),
+        }
+    }
+}
@@ -1,22 +1,31 @@
+use crate::diagnostic::Span;
+use crate::loader::{LOADER_EXPORT, LOADER_FIELD};
+use crate::validate::COMPONENT_EXTENSIONS;
 use anyhow::anyhow;
 use deno_core::error::AnyError;
 use nom::{
     branch::alt,
     bytes::complete::{is_not, tag, take_until, take_while1},
-    character::complete::{char, multispace0, multispace1, newline, none_of, space0},
+    character::complete::{char, line_ending, multispace0, multispace1, newline, none_of, space0},
     combinator::{map, opt, peek, recognize, value},
     error::Error,
     multi::{many0, many1},
     sequence::{delimited, preceded, terminated, tuple},
     IResult,
 };
+use std::collections::BTreeMap;
+
+/// Key/value pairs from the `---` delimited block at the top of a page.
+pub type Frontmatter = BTreeMap<String, String>;
 
 #[derive(Debug)]
 pub enum ASTNode {
+    Frontmatter(Frontmatter),
     Import(ImportType),
     Link(String, String),
     Heading(u8, String),
-    ReactComponent(String),
+    /// A component with its props and the `client:` directive written on it, if any.
+    ReactComponent(String, Vec<Prop>, Option<Hydration>),
     Paragraph(String),
     Text(String),
     Strong(String),
@@ -29,13 +38,195 @@ pub enum ASTNode {
     Whitespace(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ImportType {
     Named(String, String),
     Default(String, String),
     Bare(String),
 }
 
+impl ImportType {
+    pub fn source(&self) -> &str {
+        match self {
+            ImportType::Named(_, path) | ImportType::Default(_, path) | ImportType::Bare(path) => {
+                path
+            }
+        }
+    }
+
+    /// The same import from another module.
+    pub fn with_source(&self, source: String) -> ImportType {
+        match self {
+            ImportType::Named(names, _) => ImportType::Named(names.clone(), source),
+            ImportType::Default(name, _) => ImportType::Default(name.clone(), source),
+            ImportType::Bare(_) => ImportType::Bare(source),
+        }
+    }
+
+    /// The local names this import brings into scope.
+    pub fn bindings(&self) -> Vec<String> {
+        match self {
+            ImportType::Named(names, _) => names
+                .split(',')
+                .map(|name| name.trim())
+                .filter(|name| !name.is_empty())
+                .map(|name| match name.split_once(" as ") {
+                    Some((_, alias)) => alias.trim().to_string(),
+                    None => name.to_string(),
+                })
+                .collect(),
+            ImportType::Default(name, _) => vec![name.clone()],
+            ImportType::Bare(_) => Vec::new(),
+        }
+    }
+
+    /// Where the components this import brings in are rendered, from the name of the
+    /// module it imports.
+    pub fn rendering(&self) -> Rendering {
+        let name = self.source().rsplit('/').next().unwrap_or_default();
+        let name = COMPONENT_EXTENSIONS
+            .iter()
+            .find_map(|extension| name.strip_suffix(&format!(".{}", extension)))
+            .unwrap_or(name);
+        Rendering::of_name(name).1
+    }
+
+    pub fn to_statement(&self) -> String {
+        match self {
+            ImportType::Named(names, path) => format!("import {{ {} }} from '{}';", names, path),
+            ImportType::Default(name, path) => format!("import {} from '{}';", name, path),
+            ImportType::Bare(path) => format!("import '{}';", path),
+        }
+    }
+}
+
+/// An attribute written on a component, passed to it as a prop.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prop {
+    pub name: String,
+    pub value: PropValue,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropValue {
+    /// `title="Hello"`, taken as written.
+    String(String),
+    /// `count={3}`, a JavaScript expression.
+    Expression(String),
+    /// `open`, an attribute without a value.
+    True,
+}
+
+impl Prop {
+    /// The attribute as JSX.
+    pub fn to_jsx(&self) -> String {
+        match &self.value {
+            PropValue::String(value) if value.contains('"') => {
+                format!("{}='{}'", self.name, value)
+            }
+            PropValue::String(value) => format!("{}=\"{}\"", self.name, value),
+            PropValue::Expression(expression) => format!("{}={{{}}}", self.name, expression),
+            PropValue::True => self.name.clone(),
+        }
+    }
+
+    /// The prop as an entry of a JavaScript object literal.
+    pub fn to_js(&self) -> String {
+        let name = serde_json::to_string(&self.name).unwrap_or_default();
+        match &self.value {
+            PropValue::String(value) => {
+                format!(
+                    "{}: {}",
+                    name,
+                    serde_json::to_string(value).unwrap_or_default()
+                )
+            }
+            PropValue::Expression(expression) => format!("{}: ({})", name, expression),
+            PropValue::True => format!("{}: true", name),
+        }
+    }
+}
+
+/// When a component's island hydrates, picked with a `client:` directive on it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Hydration {
+    /// `client:load`, as soon as the page loads.
+    #[default]
+    Load,
+    /// `client:idle`, once the browser is idle.
+    Idle,
+    /// `client:visible`, once the component scrolls into view.
+    Visible,
+    /// `client:media="(max-width: 600px)"`, once the media query matches.
+    Media(String),
+    /// `client:none`, never: the component is rendered to HTML and ships no JS.
+    None,
+    /// `client:only`, or `client:only="Loading…"` with fallback text: skipped on the
+    /// server and rendered from scratch by the browser once the page loads.
+    Only(Option<String>),
+}
+
+/// Where a component is rendered, picked by naming its module `Name.server.tsx` or
+/// `Name.client.tsx`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Rendering {
+    /// On the server, then hydrated as an island unless `client:none` says otherwise.
+    #[default]
+    Universal,
+    /// On the server only. Its instances are never islands, so it ships no JS.
+    Server,
+    /// In the browser only. It's left out of the server entry, and its instances are
+    /// always `client:only`.
+    Client,
+}
+
+impl Rendering {
+    /// Splits the `.server` or `.client` suffix off a module's file stem, returning the
+    /// component's name and where it's rendered.
+    pub fn of_name(stem: &str) -> (&str, Rendering) {
+        if let Some(name) = stem.strip_suffix(".server") {
+            (name, Rendering::Server)
+        } else if let Some(name) = stem.strip_suffix(".client") {
+            (name, Rendering::Client)
+        } else {
+            (stem, Rendering::Universal)
+        }
+    }
+}
+
+/// Prefix of the attributes that are directives to Kaffe rather than props.
+pub const DIRECTIVE_PREFIX: &str = "client:";
+
+impl Hydration {
+    /// The strategy a `client:` directive asks for, or `None` if `prop` isn't one.
+    fn from_prop(prop: &Prop) -> Option<Self> {
+        let directive = prop.name.strip_prefix(DIRECTIVE_PREFIX)?;
+        match (directive, &prop.value) {
+            ("load", PropValue::True) => Some(Hydration::Load),
+            ("idle", PropValue::True) => Some(Hydration::Idle),
+            ("visible", PropValue::True) => Some(Hydration::Visible),
+            ("media", PropValue::String(query)) => Some(Hydration::Media(query.clone())),
+            ("none", PropValue::True) => Some(Hydration::None),
+            ("only", PropValue::True) => Some(Hydration::Only(None)),
+            ("only", PropValue::String(fallback)) => Some(Hydration::Only(Some(fallback.clone()))),
+            _ => None,
+        }
+    }
+
+    /// The directive as written, without its value.
+    pub fn directive(&self) -> String {
+        let name = match self {
+            Hydration::Load => "load",
+            Hydration::Idle => "idle",
+            Hydration::Visible => "visible",
+            Hydration::Media(_) => "media",
+            Hydration::None => "none",
+            Hydration::Only(_) => "only",
+        };
+        format!("{}{}", DIRECTIVE_PREFIX, name)
+    }
+}
+
 fn parse_bare_import(input: &str) -> IResult<&str, ImportType> {
     let (input, path) = delimited(char('"'), take_until("\""), char('"'))(input)?;
     Ok((input, ImportType::Bare(path.to_owned())))
@@ -155,6 +346,9 @@ pub fn parse_paragraph(input: &str) -> IResult<&str, ASTNode> {
         parse_code_block(content)
     } else if content.starts_with("- ") || content.starts_with("* ") {
         parse_list(content)
+    } else if let Ok(("", image)) = parse_image(content.trim()) {
+        // A block holding nothing but an image is the image itself
+        Ok((input, image))
     } else {
         Ok((input, ASTNode::Paragraph(content.trim().to_string())))
     }
@@ -182,45 +376,147 @@ fn parse_code_block(input: &str) -> IResult<&str, ASTNode> {
     ))
 }
 
+fn parse_frontmatter(input: &str) -> IResult<&str, ASTNode> {
+    let (input, _) = tag("---")(input.trim_start())?;
+    let (input, _) = line_ending(input)?;
+    let (input, block) = take_until("\n---")(input)?;
+    let (input, _) = tag("\n---")(input)?;
+    let (input, _) = opt(line_ending)(input)?;
+
+    let fields = block
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            (key.trim().to_string(), value.to_string())
+        })
+        .collect();
+
+    Ok((input, ASTNode::Frontmatter(fields)))
+}
+
 fn parse_whitespace(input: &str) -> IResult<&str, ()> {
     value((), multispace0)(input)
 }
 
-pub fn parse_markdown(input: &str) -> Result<Vec<ASTNode>, AnyError> {
-    let (_, nodes) = many0(delimited(
-        parse_whitespace,
-        alt((
-            parse_import,
-            parse_link,
-            parse_code_block,
-            parse_heading,
-            parse_react_component,
-            parse_list,
-            parse_paragraph,
-            parse_text,
-            parse_code,
-            parse_emphasis,
-            parse_strong,
-            parse_image,
-            parse_blockquote,
-        )),
-        parse_whitespace,
+/// Parsed nodes alongside the source span each one was parsed from.
+#[derive(Debug)]
+pub struct Document {
+    pub nodes: Vec<ASTNode>,
+    pub spans: Vec<Span>,
+}
+
+impl Document {
+    pub fn iter(&self) -> impl Iterator<Item = (&ASTNode, &Span)> {
+        self.nodes.iter().zip(self.spans.iter())
+    }
+}
+
+fn parse_node(input: &str) -> IResult<&str, ASTNode> {
+    alt((
+        parse_import,
+        parse_link,
+        parse_code_block,
+        parse_heading,
+        parse_react_component,
+        parse_list,
+        parse_paragraph,
+        parse_text,
+        parse_code,
+        parse_emphasis,
+        parse_strong,
+        parse_image,
+        parse_blockquote,
     ))(input)
-    .map_err(|e| anyhow!("Failed to parse markdown: {:?}", e))?;
+}
+
+pub fn parse_markdown(input: &str) -> Result<Vec<ASTNode>, AnyError> {
+    Ok(parse_document(input)?.nodes)
+}
+
+pub fn parse_document(source: &str) -> Result<Document, AnyError> {
+    let mut document = Document {
+        nodes: Vec::new(),
+        spans: Vec::new(),
+    };
+    // Node parsers trim their input, so the remainder is not always a suffix of `source`
+    // and the offset has to come from where the remaining slice points into it
+    let offset = |rest: &str| rest.as_ptr() as usize - source.as_ptr() as usize;
+
+    let (mut input, frontmatter) = opt(parse_frontmatter)(source)
+        .map_err(|e| anyhow!("Failed to parse frontmatter: {:?}", e))?;
+    if let Some(frontmatter) = frontmatter {
+        let span = Span::new(source, 0, offset(input));
+        let loader = match &frontmatter {
+            ASTNode::Frontmatter(fields) => fields.get(LOADER_FIELD).cloned(),
+            _ => None,
+        };
+        document.nodes.push(frontmatter);
+        document.spans.push(span);
+        // Imported like any other module, so it's resolved, validated and tracked the same
+        if let Some(path) = loader {
+            document.nodes.push(ASTNode::Import(ImportType::Named(
+                LOADER_EXPORT.to_string(),
+                path,
+            )));
+            document.spans.push(span);
+        }
+    }
+
+    // Equivalent to `many0(delimited(parse_whitespace, parse_node, parse_whitespace))`,
+    // but keeps track of where each node starts and ends
+    loop {
+        let (rest, _) =
+            parse_whitespace(input).map_err(|e| anyhow!("Failed to parse markdown: {:?}", e))?;
+        let start = offset(rest.trim_start());
+        match parse_node(rest) {
+            Ok((rest, node)) => {
+                if offset(rest) == offset(input) {
+                    return Err(anyhow!(
+                        "Failed to parse markdown: no progress at byte {}",
+                        start
+                    ));
+                }
+                let end = offset(rest).max(start);
+                document.nodes.push(node);
+                document.spans.push(Span::new(source, start, end));
+                input = rest;
+            }
+            Err(nom::Err::Error(_)) => break,
+            Err(e) => return Err(anyhow!("Failed to parse markdown: {:?}", e)),
+        }
+    }
 
-    Ok(nodes)
+    Ok(document)
 }
 
 fn parse_react_component(input: &str) -> IResult<&str, ASTNode, Error<&str>> {
     // Parse a React component in the form of <ComponentName> or <ComponentName />
     let (input, _) = tag("<")(input.trim())?; // Parse the opening '<'
     let (input, component_name) = take_while1(|c: char| c.is_alphanumeric())(input)?; // Parse component name
+    let (input, mut props) = many0(preceded(multispace1, parse_prop))(input)?; // Parse attributes
     let (input, _) = multispace0(input)?; // Allow for any spaces
 
+    // Directives are taken out of the props, and any left are reported by validation
+    let mut hydration = None;
+    props.retain(|prop| match Hydration::from_prop(prop) {
+        Some(strategy) => {
+            hydration = Some(strategy);
+            false
+        }
+        None => true,
+    });
+    let component = ASTNode::ReactComponent(component_name.to_string(), props, hydration);
+
     // Check if the tag is self-closing or has children
     if let Ok((input, _)) = tag::<_, _, Error<&str>>("/>")(input) {
         // If it's a self-closing tag, we successfully parsed it
-        return Ok((input, ASTNode::ReactComponent(component_name.to_string())));
+        return Ok((input, component));
     }
 
     // If not self-closing, it should have a closing tag
@@ -230,81 +526,124 @@ fn parse_react_component(input: &str) -> IResult<&str, ASTNode, Error<&str>> {
     let (input, _) = tag(closing_tag.as_str())(input)?; // Parse the closing tag
 
     // Return the parsed component
-    Ok((input, ASTNode::ReactComponent(component_name.to_string())))
-}
-
-pub async fn generate_html(
-    ast: &[ASTNode],
-) -> Result<(String, Vec<String>, Vec<String>), anyhow::Error> {
-    let mut html = String::new();
-    let mut imports = Vec::new();
-    let mut react_components = Vec::new();
-
-    for node in ast {
-        match node {
-            ASTNode::Import(import_type) => match import_type {
-                ImportType::Named(component, path) => {
-                    imports.push(format!("import {{ {} }} from '{}';", component, path));
-                    react_components.extend(component.split(',').map(|s| s.trim().to_string()));
-                }
-                ImportType::Default(component, path) => {
-                    imports.push(format!("import {} from '{}';", component, path));
-                    react_components.push(component.to_string());
-                }
-                ImportType::Bare(path) => {
-                    imports.push(format!("import '{}';", path));
-                }
+    Ok((input, component))
+}
+
+fn parse_prop(input: &str) -> IResult<&str, Prop> {
+    let (input, name) =
+        take_while1(|c: char| c.is_alphanumeric() || c == '-' || c == '_' || c == ':')(input)?;
+    let (input, value) = opt(preceded(
+        delimited(space0, char('='), space0),
+        alt((
+            map(
+                delimited(char('"'), take_until("\""), char('"')),
+                |value: &str| PropValue::String(value.to_string()),
+            ),
+            map(
+                delimited(char('\''), take_until("'"), char('\'')),
+                |value: &str| PropValue::String(value.to_string()),
+            ),
+            map(parse_expression, |expression| {
+                PropValue::Expression(expression.trim().to_string())
+            }),
+        )),
+    ))(input)?;
+    Ok((
+        input,
+        Prop {
+            name: name.to_string(),
+            value: value.unwrap_or(PropValue::True),
+        },
+    ))
+}
+
+/// A `{...}` expression, returning what's between the outer braces. Braces inside
+/// strings and template literals don't count.
+fn parse_expression(input: &str) -> IResult<&str, &str> {
+    let (rest, _) = char('{')(input)?;
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in rest.char_indices() {
+        match quote {
+            Some(_) if escaped => escaped = false,
+            Some(_) if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => match c {
+                '"' | '\'' | '`' => quote = Some(c),
+                '{' => depth += 1,
+                '}' if depth == 0 => return Ok((&rest[i + 1..], &rest[..i])),
+                '}' => depth -= 1,
+                _ => {}
             },
+        }
+    }
+    Err(nom::Err::Error(Error::new(
+        input,
+        nom::error::ErrorKind::TakeUntil,
+    )))
+}
 
-            ASTNode::Heading(level, content) => {
-                html.push_str(&format!("<h{}>{}</h{}>\n", level, content, level));
-            }
-            ASTNode::Paragraph(content) => {
-                html.push_str(&format!("<p>{}</p>\n", content));
-            }
-            ASTNode::CodeBlock(content, lang) => {
-                html.push_str(&format!(
-                    "<pre><code className=\"language-{}\">{}</code></pre>\n",
-                    lang, content
-                ));
-            }
-            ASTNode::Text(content) => {
-                html.push_str(&format!("{}\n", content));
-            }
-            ASTNode::Strong(content) => {
-                html.push_str(&format!("<strong>{}</strong>", content));
-            }
-            ASTNode::Emphasis(content) => {
-                html.push_str(&format!("<em>{}</em>", content));
-            }
-            ASTNode::Code(content) => {
-                html.push_str(&format!("<code>{}</code>", content));
-            }
-            ASTNode::Link(text, url) => {
-                html.push_str(&format!("<a href=\"{}\">{}</a>", url, text));
-            }
-            ASTNode::Image(alt_text, url) => {
-                html.push_str(&format!("<img src=\"{}\" alt=\"{}\">", url, alt_text));
-            }
-            ASTNode::List(items) => {
-                html.push_str("<ul>\n");
-                for item in items {
-                    html.push_str(&format!("  <li>{}</li>\n", item));
-                }
-                html.push_str("</ul>\n");
-            }
-            ASTNode::BlockQuote(content) => {
-                html.push_str(&format!("<blockquote>{}</blockquote>\n", content));
-            }
-            ASTNode::ReactComponent(component_name) => {
-                if !react_components.contains(component_name) {
-                    react_components.push(component_name.clone());
-                }
-                html.push_str(&format!("<{}></{}>\n", component_name, component_name));
-            }
-            ASTNode::Whitespace(_) => {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn component(source: &str) -> (Vec<Prop>, Option<Hydration>) {
+        match parse_markdown(source).unwrap().into_iter().next() {
+            Some(ASTNode::ReactComponent(_, props, hydration)) => (props, hydration),
+            other => panic!("expected a component in {:?}, got {:?}", source, other),
+        }
+    }
+
+    #[test]
+    fn takes_hydration_directives_out_of_the_props() {
+        let cases = [
+            ("client:load", Hydration::Load),
+            ("client:idle", Hydration::Idle),
+            ("client:visible", Hydration::Visible),
+            (
+                "client:media=\"(max-width: 600px)\"",
+                Hydration::Media("(max-width: 600px)".to_string()),
+            ),
+            ("client:none", Hydration::None),
+            ("client:only", Hydration::Only(None)),
+            (
+                "client:only=\"Loading…\"",
+                Hydration::Only(Some("Loading…".to_string())),
+            ),
+        ];
+        for (directive, expected) in cases {
+            let source = format!("<Counter start={{3}} {} label=\"Count\" />\n", directive);
+            let (props, hydration) = component(&source);
+            assert_eq!(hydration, Some(expected), "{}", directive);
+            let names: Vec<_> = props.iter().map(|prop| prop.name.as_str()).collect();
+            assert_eq!(names, ["start", "label"], "{}", directive);
         }
     }
 
-    Ok((html, imports, react_components))
+    #[test]
+    fn leaves_components_without_a_directive_to_the_default() {
+        let (props, hydration) = component("<Counter></Counter>\n");
+        assert!(props.is_empty());
+        assert_eq!(hydration, None);
+    }
+
+    #[test]
+    fn keeps_malformed_directives_as_props_for_validation() {
+        let (props, hydration) =
+            component("<Counter client:media client:load=\"yes\" client:hover />\n");
+        assert_eq!(hydration, None);
+        let names: Vec<_> = props.iter().map(|prop| prop.name.as_str()).collect();
+        assert_eq!(names, ["client:media", "client:load", "client:hover"]);
+    }
+
+    #[test]
+    fn names_directives_as_written() {
+        assert_eq!(
+            Hydration::Media("print".to_string()).directive(),
+            "client:media"
+        );
+        assert_eq!(Hydration::Only(None).directive(), "client:only");
+    }
 }
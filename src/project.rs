@@ -0,0 +1,284 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::parser::Frontmatter;
+
+/// Files Kaffe ships with a default for, which a project can override by placing its own
+/// copy at the same path relative to the project root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProjectFile {
+    ServerEntry,
+    ClientEntry,
+    HtmlTemplate,
+    UrlPolyfill,
+}
+
+impl ProjectFile {
+    pub const ALL: [ProjectFile; 4] = [
+        ProjectFile::ServerEntry,
+        ProjectFile::ClientEntry,
+        ProjectFile::HtmlTemplate,
+        ProjectFile::UrlPolyfill,
+    ];
+
+    pub fn relative_path(&self) -> &'static str {
+        match self {
+            ProjectFile::ServerEntry => "client/src/server-entry.template",
+            ProjectFile::ClientEntry => "client/src/client-entry.template",
+            ProjectFile::HtmlTemplate => "client/template.html",
+            ProjectFile::UrlPolyfill => "client/polyfills/URL.js",
+        }
+    }
+
+    /// The frontmatter field a page can use to pick its own copy of this file.
+    pub fn frontmatter_key(&self) -> Option<&'static str> {
+        match self {
+            ProjectFile::ServerEntry => Some("server_entry"),
+            ProjectFile::ClientEntry => Some("client_entry"),
+            ProjectFile::HtmlTemplate => Some("template"),
+            ProjectFile::UrlPolyfill => None,
+        }
+    }
+
+    /// The file name that overrides this file for every page in a directory and below.
+    pub fn directory_override(&self) -> Option<&'static str> {
+        match self {
+            ProjectFile::ServerEntry => Some("_server-entry.template"),
+            ProjectFile::ClientEntry => Some("_client-entry.template"),
+            ProjectFile::HtmlTemplate => Some("_template.html"),
+            ProjectFile::UrlPolyfill => None,
+        }
+    }
+
+    /// Placeholders that must appear for the generated page to work.
+    pub fn required_placeholders(&self) -> &'static [&'static str] {
+        match self {
+            ProjectFile::ServerEntry => &["%{{ REPLACE_IMPORTS }}%", "%{{ REPLACE_CONTENT }}%"],
+            ProjectFile::ClientEntry => &["%{{ REPLACE_IMPORTS }}%", "%{{ REPLACE_ISLANDS }}%"],
+            // Variables in `template.html` are checked by the template engine instead
+            ProjectFile::HtmlTemplate | ProjectFile::UrlPolyfill => &[],
+        }
+    }
+
+    pub fn default_contents(&self) -> &'static str {
+        match self {
+            ProjectFile::ServerEntry => include_str!("../client/src/server-entry.template"),
+            ProjectFile::ClientEntry => include_str!("../client/src/client-entry.template"),
+            ProjectFile::HtmlTemplate => include_str!("../client/template.html"),
+            ProjectFile::UrlPolyfill => include_str!("../client/polyfills/URL.js"),
+        }
+    }
+}
+
+/// A template chosen for a page, along with where it was loaded from.
+#[derive(Debug, Clone)]
+pub struct TemplateFile {
+    pub path: PathBuf,
+    pub contents: String,
+}
+
+/// Starter files written by `kaffe init` in addition to the [`ProjectFile`] defaults.
+const SCAFFOLD: &[(&str, &str)] = &[
+    (
+        "client/package.json",
+        r#"{
+  "name": "client",
+  "private": true,
+  "type": "module",
+  "dependencies": {
+    "fast-text-encoding": "^1.0.6",
+    "react": "^18.2.0",
+    "react-dom": "^18.2.0"
+  }
+}
+"#,
+    ),
+    (
+        "client/tsconfig.json",
+        r#"{
+  "compilerOptions": {
+    "target": "esnext",
+    "module": "esnext",
+    "moduleResolution": "node",
+    "strict": true,
+    "jsx": "react-jsx",
+    "baseUrl": ".",
+    "paths": {
+      "@/components/*": ["src/components/*"]
+    }
+  },
+  "include": ["src"]
+}
+"#,
+    ),
+    (
+        "client/src/components/Counter.tsx",
+        r#"import { useState } from "react";
+
+const Counter = () => {
+  const [count, setCount] = useState(0);
+  return <button onClick={() => setCount(count + 1)}>Clicked {count} times</button>;
+};
+
+export default Counter;
+"#,
+    ),
+    (
+        "kaffe.toml",
+        r#"[site]
+title = "My Kaffe site"
+"#,
+    ),
+    (
+        "examples/index.mdx",
+        r#"import Counter from "@/components/Counter";
+
+# Hello from Kaffe
+
+<Counter />
+"#,
+    ),
+];
+
+/// A site being built, with every input and output resolved relative to its root.
+#[derive(Debug, Clone)]
+pub struct Project {
+    pub root: PathBuf,
+}
+
+impl Project {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn path(&self, relative: impl AsRef<Path>) -> PathBuf {
+        self.root.join(relative)
+    }
+
+    /// Where generated entries and bundles are written.
+    pub fn build_dir(&self) -> PathBuf {
+        self.path("client/dist")
+    }
+
+    /// Intermediate build state. It lives under the client directory so the generated
+    /// entries resolve packages from `client/node_modules`.
+    pub fn cache_dir(&self) -> PathBuf {
+        self.build_dir().join(".cache")
+    }
+
+    /// Reads the project's copy of `file`, falling back to the embedded default.
+    pub fn load(&self, file: ProjectFile) -> Result<String> {
+        let path = self.path(file.relative_path());
+        match fs::read_to_string(&path) {
+            Ok(contents) => Ok(contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(file.default_contents().into()),
+            Err(e) => Err(Error::Template {
+                file: path,
+                message: e.to_string(),
+            }),
+        }
+    }
+
+    /// Loads the template `file` used for `page`, looking in order at the page's
+    /// frontmatter, the nearest directory override between the page and `input_dir`,
+    /// the project's copy and finally the embedded default.
+    pub fn template_for_page(
+        &self,
+        file: ProjectFile,
+        page: &Path,
+        input_dir: &Path,
+        frontmatter: &Frontmatter,
+    ) -> Result<TemplateFile> {
+        let page_dir = page.parent().unwrap_or(input_dir);
+
+        let from_frontmatter = file
+            .frontmatter_key()
+            .and_then(|key| frontmatter.get(key))
+            .map(|template| page_dir.join(template));
+        if let Some(path) = &from_frontmatter {
+            if !path.is_file() {
+                return Err(Error::Template {
+                    file: page.to_path_buf(),
+                    message: format!(
+                        "`{}` in frontmatter points to {}, which does not exist",
+                        file.frontmatter_key().unwrap_or_default(),
+                        path.display()
+                    ),
+                });
+            }
+        }
+
+        let from_directory = file.directory_override().and_then(|name| {
+            page_dir
+                .ancestors()
+                .take_while(|dir| dir.starts_with(input_dir))
+                .map(|dir| dir.join(name))
+                .find(|path| path.is_file())
+        });
+
+        let (path, contents) = match from_frontmatter.or(from_directory) {
+            Some(path) => {
+                let contents = fs::read_to_string(&path).map_err(|e| Error::io(&path, e))?;
+                (path, contents)
+            }
+            None => (self.path(file.relative_path()), self.load(file)?),
+        };
+
+        let missing: Vec<_> = file
+            .required_placeholders()
+            .iter()
+            .filter(|placeholder| !contents.contains(*placeholder))
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Err(Error::Template {
+                file: path,
+                message: format!("missing required placeholder(s) {}", missing.join(", ")),
+            });
+        }
+
+        Ok(TemplateFile { path, contents })
+    }
+
+    /// Returns a path to `file` on disk, writing the embedded default into `dir` when the
+    /// project has no copy of its own.
+    pub fn materialize(&self, file: ProjectFile, dir: &Path) -> Result<PathBuf> {
+        let path = self.path(file.relative_path());
+        if path.is_file() {
+            return Ok(path);
+        }
+
+        let file_name = Path::new(file.relative_path())
+            .file_name()
+            .unwrap_or_default();
+        let path = dir.join(file_name);
+        fs::write(&path, file.default_contents()).map_err(|e| Error::io(&path, e))?;
+        Ok(path)
+    }
+
+    /// Writes the default templates and a starter page into the project root, leaving any
+    /// existing files untouched. Returns the files that were created.
+    pub fn init(&self) -> Result<Vec<PathBuf>> {
+        let files = ProjectFile::ALL
+            .iter()
+            .map(|file| (file.relative_path(), file.default_contents()))
+            .chain(SCAFFOLD.iter().copied());
+
+        let mut created = Vec::new();
+        for (relative_path, contents) in files {
+            let path = self.path(relative_path);
+            if path.exists() {
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+            }
+            fs::write(&path, contents).map_err(|e| Error::io(&path, e))?;
+            created.push(path);
+        }
+
+        Ok(created)
+    }
+}
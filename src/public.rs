@@ -0,0 +1,221 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use walkdir::WalkDir;
+
+use crate::cache::hash_bytes;
+use crate::error::{Error, Result};
+use crate::parser::ASTNode;
+use crate::project::Project;
+
+/// Directory, relative to the project root, mirrored into the output directory as it is.
+pub const PUBLIC_DIR: &str = "public";
+
+/// Hex digits of the content hash put in fingerprinted file names.
+const FINGERPRINT_LENGTH: usize = 8;
+
+/// The `[public]` table of `kaffe.toml`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PublicConfig {
+    /// Glob patterns for files that aren't copied. Patterns without a `/` are matched
+    /// against every file and directory name, others against paths relative to
+    /// `public/`.
+    pub ignore: Vec<String>,
+    /// Put a hash of each file's contents in its name, rewriting references to it.
+    pub fingerprint: bool,
+}
+
+impl Default for PublicConfig {
+    fn default() -> Self {
+        Self {
+            ignore: vec![".*".to_string()],
+            fingerprint: false,
+        }
+    }
+}
+
+/// The files copied from [`PUBLIC_DIR`], keyed by the URL pages and templates refer to
+/// them with, like `/fonts/inter.woff2`.
+#[derive(Debug, Clone, Default)]
+pub struct PublicFiles {
+    urls: BTreeMap<String, String>,
+}
+
+impl PublicFiles {
+    /// Copies every file in the project's public directory that isn't ignored into
+    /// `output_dir`, at the same path or a fingerprinted one. Files the output already has
+    /// an up to date copy of are left alone.
+    pub fn copy(project: &Project, output_dir: &Path, config: &PublicConfig) -> Result<Self> {
+        let public_dir = project.path(PUBLIC_DIR);
+        let mut urls = BTreeMap::new();
+        if !public_dir.is_dir() {
+            return Ok(Self { urls });
+        }
+
+        let entries = WalkDir::new(&public_dir)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|entry| {
+                let name = entry.file_name().to_string_lossy();
+                entry.depth() == 0 || !ignored_name(&config.ignore, &name)
+            });
+        for entry in entries {
+            let entry = entry.map_err(|e| Error::io(&public_dir, e.into()))?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let relative = url_path(
+                entry
+                    .path()
+                    .strip_prefix(&public_dir)
+                    .unwrap_or(entry.path()),
+            );
+            if ignored_path(&config.ignore, &relative) {
+                continue;
+            }
+            if relative.starts_with("static/") {
+                return Err(Error::Config {
+                    file: entry.path().to_path_buf(),
+                    message: "files in public/static would clash with bundled assets".to_string(),
+                });
+            }
+
+            let copied = if config.fingerprint {
+                let contents = fs::read(entry.path()).map_err(|e| Error::io(entry.path(), e))?;
+                fingerprinted(&relative, &hash_bytes(&contents)[..FINGERPRINT_LENGTH])
+            } else {
+                relative.clone()
+            };
+            copy_if_changed(entry.path(), &output_dir.join(&copied))?;
+            urls.insert(format!("/{}", relative), format!("/{}", copied));
+        }
+
+        Ok(Self { urls })
+    }
+
+    pub fn contains(&self, url: &str) -> bool {
+        self.urls.contains_key(url)
+    }
+
+    /// The URL to reference a public file by, which differs from `url` once it's
+    /// fingerprinted. Queries and fragments are kept.
+    pub fn get(&self, url: &str) -> Option<String> {
+        let end = url.find(['?', '#']).unwrap_or(url.len());
+        let (path, rest) = url.split_at(end);
+        self.urls
+            .get(path)
+            .map(|copied| format!("{}{}", copied, rest))
+    }
+
+    /// Public files whose URL changed when they were fingerprinted.
+    pub fn fingerprinted(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.urls.iter().filter(|(url, copied)| url != copied)
+    }
+
+    /// Points Markdown links to public files at their fingerprinted copies.
+    pub fn rewrite_links(&self, nodes: &mut [ASTNode]) {
+        for node in nodes {
+            if let ASTNode::Link(_, url) = node {
+                if let Some(copied) = self.get(url) {
+                    *url = copied;
+                }
+            }
+        }
+    }
+}
+
+/// Whether a file or directory called `name` is ignored wherever it is.
+fn ignored_name(patterns: &[String], name: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| !pattern.contains('/') && glob_match(pattern, name))
+}
+
+/// Whether the file at `relative` is ignored, by its path or its name.
+fn ignored_path(patterns: &[String], relative: &str) -> bool {
+    let name = relative.rsplit('/').next().unwrap_or(relative);
+    ignored_name(patterns, name)
+        || patterns
+            .iter()
+            .any(|pattern| pattern.contains('/') && glob_match(pattern, relative))
+}
+
+/// Matches `text` against a glob where `*` stands for anything but `/`, `**` for
+/// anything at all, and `?` for a single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    if let Some(rest) = pattern.strip_prefix("**") {
+        let rest = rest.strip_prefix('/').unwrap_or(rest);
+        return text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(text.len()))
+            .any(|i| glob_match(rest, &text[i..]));
+    }
+    let mut pattern_chars = pattern.chars();
+    match pattern_chars.next() {
+        None => text.is_empty(),
+        Some('*') => {
+            let rest = pattern_chars.as_str();
+            let end = text.find('/').unwrap_or(text.len());
+            text.char_indices()
+                .map(|(i, _)| i)
+                .chain(std::iter::once(text.len()))
+                .take_while(|&i| i <= end)
+                .any(|i| glob_match(rest, &text[i..]))
+        }
+        Some(c) => {
+            let mut text_chars = text.chars();
+            match text_chars.next() {
+                Some(t) if (c == '?' && t != '/') || c == t => {
+                    glob_match(pattern_chars.as_str(), text_chars.as_str())
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+/// `relative` with `hash` inserted before its extension.
+fn fingerprinted(relative: &str, hash: &str) -> String {
+    let (dir, name) = match relative.rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/", dir), name),
+        None => (String::new(), relative),
+    };
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}{}.{}.{}", dir, stem, hash, extension)
+        }
+        _ => format!("{}{}.{}", dir, name, hash),
+    }
+}
+
+/// `path` with `/` separators.
+fn url_path(path: &Path) -> String {
+    path.iter()
+        .map(|c| c.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn copy_if_changed(source: &Path, target: &Path) -> Result<()> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+    let up_to_date = match (modified(source), modified(target)) {
+        (Ok(source_time), Ok(target_time)) => {
+            target_time >= source_time
+                && fs::metadata(source).map(|m| m.len()).ok()
+                    == fs::metadata(target).map(|m| m.len()).ok()
+        }
+        _ => false,
+    };
+    if up_to_date {
+        return Ok(());
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+    }
+    fs::copy(source, target).map_err(|e| Error::io(source, e))?;
+    Ok(())
+}
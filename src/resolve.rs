@@ -0,0 +1,462 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::diagnostic::Diagnostic;
+use crate::error::{Error, Result};
+use crate::imports::{resolve_path, specifier_between, string_literals};
+use crate::mapping::ComponentMapping;
+use crate::parser::{ASTNode, Document, ImportType, Rendering};
+use crate::project::Project;
+use crate::validate::{closest_match, ComponentIndex, COMPONENT_EXTENSIONS};
+
+/// The client's TypeScript config, whose `compilerOptions.paths` aliases pages can import
+/// through.
+pub const TSCONFIG_FILE: &str = "client/tsconfig.json";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TsConfig {
+    #[serde(default)]
+    compiler_options: CompilerOptions,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CompilerOptions {
+    base_url: Option<String>,
+    #[serde(default)]
+    paths: BTreeMap<String, Vec<String>>,
+}
+
+/// One `paths` entry, like `"@/components/*": ["src/components/*"]`. Patterns have at
+/// most one `*`, which stands for the same text in the specifier and the targets.
+#[derive(Debug)]
+struct Alias {
+    pattern: String,
+    targets: Vec<String>,
+}
+
+impl Alias {
+    /// The text `*` matched when `specifier` matches the pattern.
+    fn matches<'a>(&self, specifier: &'a str) -> Option<&'a str> {
+        match self.pattern.split_once('*') {
+            Some((prefix, suffix)) => specifier.strip_prefix(prefix)?.strip_suffix(suffix),
+            None => (specifier == self.pattern).then_some(""),
+        }
+    }
+}
+
+/// Resolves the imports of pages like the bundler and editor do: relative to the page
+/// that declares them, or through the aliases in [`TSCONFIG_FILE`].
+#[derive(Debug, Default)]
+pub struct ImportResolver {
+    aliases: Vec<Alias>,
+    /// The parent of the component directory, which generated entries used to import
+    /// `./components/...` from.
+    legacy_dir: PathBuf,
+}
+
+impl ImportResolver {
+    /// Reads the aliases from the project's tsconfig, if it has one.
+    pub fn load(project: &Project, component_dir: &Path) -> Result<Self> {
+        let path = project.path(TSCONFIG_FILE);
+        let config = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                serde_json::from_str(&strip_jsonc(&contents)).map_err(|e| Error::Config {
+                    file: path.clone(),
+                    message: e.to_string(),
+                })?
+            }
+            Err(_) => TsConfig::default(),
+        };
+
+        let config_dir = path.parent().unwrap_or(Path::new(""));
+        let options = config.compiler_options;
+        let base = config_dir.join(options.base_url.as_deref().unwrap_or("."));
+        let base = base.canonicalize().unwrap_or(base);
+        let mut aliases: Vec<_> = options
+            .paths
+            .into_iter()
+            .map(|(pattern, targets)| Alias {
+                pattern,
+                targets: targets
+                    .iter()
+                    .map(|target| {
+                        let target = target.strip_prefix("./").unwrap_or(target);
+                        base.join(target).to_string_lossy().into_owned()
+                    })
+                    .collect(),
+            })
+            .collect();
+        // TypeScript prefers the pattern with the longest prefix before the `*`
+        aliases.sort_by_key(|alias| {
+            std::cmp::Reverse(alias.pattern.split('*').next().unwrap_or_default().len())
+        });
+
+        let legacy_dir = component_dir
+            .parent()
+            .unwrap_or(Path::new(""))
+            .to_path_buf();
+        Ok(Self {
+            aliases,
+            legacy_dir: legacy_dir.canonicalize().unwrap_or(legacy_dir),
+        })
+    }
+
+    /// Whether `specifier` names a local file rather than a package.
+    pub fn is_local(&self, specifier: &str) -> bool {
+        is_relative(specifier)
+            || Path::new(specifier).is_absolute()
+            || self.matches_alias(specifier)
+    }
+
+    /// The canonical file `specifier` refers to when imported from a file in `dir`.
+    pub fn resolve(&self, specifier: &str, dir: &Path) -> Option<PathBuf> {
+        if is_relative(specifier) {
+            return resolve_path(&dir.join(specifier));
+        }
+        if Path::new(specifier).is_absolute() {
+            return resolve_path(Path::new(specifier));
+        }
+        self.aliases.iter().find_map(|alias| {
+            let matched = alias.matches(specifier)?;
+            alias
+                .targets
+                .iter()
+                .find_map(|target| resolve_path(Path::new(&target.replacen('*', matched, 1))))
+        })
+    }
+
+    /// Local files `file` imports, relatively or through an alias, in the order they
+    /// appear in its `contents`.
+    pub fn imports_of(&self, file: &Path, contents: &str) -> Vec<PathBuf> {
+        self.local_imports(file, contents)
+            .into_iter()
+            .map(|(_, path)| path)
+            .collect()
+    }
+
+    /// Like [`ImportResolver::imports_of`], but keeps the specifier each file was imported
+    /// with.
+    pub fn local_imports<'a>(&self, file: &Path, contents: &'a str) -> Vec<(&'a str, PathBuf)> {
+        let dir = file.parent().unwrap_or(Path::new(""));
+        string_literals(contents)
+            .filter(|literal| is_relative(literal) || self.matches_alias(literal))
+            .filter_map(|literal| Some((literal, self.resolve(literal, dir)?)))
+            .collect()
+    }
+
+    fn matches_alias(&self, specifier: &str) -> bool {
+        self.aliases
+            .iter()
+            .any(|alias| alias.matches(specifier).is_some())
+    }
+
+    /// The specifier a file in `dir` would import `target` with, through an alias if one
+    /// covers it.
+    pub fn specifier_for(&self, target: &Path, dir: &Path) -> String {
+        let target = target.canonicalize().unwrap_or(target.to_path_buf());
+        let target = match target.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if COMPONENT_EXTENSIONS.contains(&ext) => target.with_extension(""),
+            _ => target,
+        };
+        let target_str = target.to_string_lossy();
+
+        for alias in &self.aliases {
+            let Some((prefix, suffix)) = alias.pattern.split_once('*') else {
+                continue;
+            };
+            for pattern in &alias.targets {
+                let Some((target_prefix, target_suffix)) = pattern.split_once('*') else {
+                    continue;
+                };
+                if let Some(matched) = target_str
+                    .strip_prefix(target_prefix)
+                    .and_then(|rest| rest.strip_suffix(target_suffix))
+                {
+                    return format!("{}{}{}", prefix, matched, suffix);
+                }
+            }
+        }
+
+        let dir = dir.canonicalize().unwrap_or(dir.to_path_buf());
+        specifier_between(&dir, &target)
+    }
+
+    /// Resolves the path of a `--component-map` rule, which is relative to the parent of
+    /// the component directory or goes through an alias, to an absolute one.
+    pub fn resolve_mapping(&self, mapping: &ComponentMapping) -> Result<ComponentMapping> {
+        let file = self
+            .resolve(&mapping.path, &self.legacy_dir)
+            .ok_or_else(|| Error::Config {
+                file: PathBuf::from("--component-map"),
+                message: format!(
+                    "cannot find `{}` mapped to `{}` in {}",
+                    mapping.path,
+                    mapping.element,
+                    self.legacy_dir.display()
+                ),
+            })?;
+        Ok(ComponentMapping {
+            path: file.to_string_lossy().into_owned(),
+            ..mapping.clone()
+        })
+    }
+}
+
+/// Resolves every local import in the page at `page`, returning the file for each
+/// specifier that was found along with diagnostics for the ones that weren't.
+///
+/// Imports of `./components/...` that don't exist next to the page fall back to the
+/// component directory with a warning, since that's where generated entries used to
+/// find them.
+pub fn resolve_imports(
+    document: &Document,
+    page: &Path,
+    resolver: &ImportResolver,
+    index: &ComponentIndex,
+) -> (BTreeMap<String, PathBuf>, Vec<Diagnostic>) {
+    let dir = page.parent().unwrap_or(Path::new(""));
+    let mut resolved = BTreeMap::new();
+    let mut diagnostics = Vec::new();
+
+    for (node, span) in document.iter() {
+        let ASTNode::Import(import) = node else {
+            continue;
+        };
+        let source = import.source();
+        if !resolver.is_local(source) || resolved.contains_key(source) {
+            continue;
+        }
+
+        if let Some(file) = resolver.resolve(source, dir) {
+            resolved.insert(source.to_string(), file);
+        } else if let Some(file) = source
+            .starts_with("./components/")
+            .then(|| resolver.resolve(source, &resolver.legacy_dir))
+            .flatten()
+        {
+            diagnostics.push(
+                Diagnostic::warning(format!(
+                    "\"{}\" is resolved from the component directory, not from the page",
+                    source
+                ))
+                .with_span(*span)
+                .with_help(format!(
+                    "import it as \"{}\"",
+                    resolver.specifier_for(&file, dir)
+                )),
+            );
+            resolved.insert(source.to_string(), file);
+        } else {
+            diagnostics.push(unresolved(import, dir, resolver, index).with_span(*span));
+        }
+    }
+
+    (resolved, diagnostics)
+}
+
+/// The file a generated page's `import` refers to: the one [`resolve_imports`] found, or
+/// the path of a mapped component, which is resolved up front.
+pub fn import_file<'a>(
+    import: &'a ImportType,
+    resolved: &'a BTreeMap<String, PathBuf>,
+) -> Option<&'a Path> {
+    let source = Path::new(import.source());
+    match resolved.get(import.source()) {
+        Some(file) => Some(file),
+        None if source.is_absolute() => Some(source),
+        None => None,
+    }
+}
+
+fn unresolved(
+    import: &ImportType,
+    dir: &Path,
+    resolver: &ImportResolver,
+    index: &ComponentIndex,
+) -> Diagnostic {
+    let source = import.source();
+    let (name, _) = Rendering::of_name(source.rsplit('/').next().unwrap_or(source));
+    let mut diagnostic = Diagnostic::error(format!(
+        "Cannot find \"{}\" imported from {}",
+        source,
+        dir.display()
+    ));
+    // Only PascalCase modules are treated as components worth suggesting
+    if name.starts_with(|c: char| c.is_ascii_uppercase()) {
+        if let Some(file) = closest_match(name, index.names()).and_then(|c| index.path(c)) {
+            diagnostic = diagnostic.with_help(format!(
+                "did you mean \"{}\"?",
+                resolver.specifier_for(file, dir)
+            ));
+        }
+    }
+    diagnostic
+}
+
+fn is_relative(specifier: &str) -> bool {
+    specifier.starts_with("./") || specifier.starts_with("../")
+}
+
+/// Removes the comments and trailing commas tsconfig files are allowed to have.
+fn strip_jsonc(source: &str) -> String {
+    let mut output = String::with_capacity(source.len());
+    let mut chars = source.chars().peekable();
+    let mut in_string = false;
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            output.push(c);
+            match c {
+                '\\' => output.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                output.push(c);
+            }
+            ('/', Some('/')) => {
+                while chars.peek().is_some_and(|&c| c != '\n') {
+                    chars.next();
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+            }
+            (']' | '}', _) => {
+                let trimmed = output.trim_end().len();
+                if output[..trimmed].ends_with(',') {
+                    output.truncate(trimmed - 1);
+                }
+                output.push(c);
+            }
+            _ => output.push(c),
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A project whose tsconfig maps `@/components/*` and, less specifically, `@/*`.
+    fn project() -> (tempfile::TempDir, Project) {
+        let dir = tempfile::tempdir().unwrap();
+        let project = Project::new(dir.path().canonicalize().unwrap());
+        for file in [
+            "src/ui/Card.tsx",
+            "src/components/Card.tsx",
+            "src/lib/format.ts",
+        ] {
+            let path = project.path("client").join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "export default 1;").unwrap();
+        }
+        fs::write(
+            project.path(TSCONFIG_FILE),
+            r#"{
+                // Comments and trailing commas are allowed
+                "compilerOptions": {
+                    "baseUrl": ".",
+                    "paths": {
+                        "@/*": ["./src/*"],
+                        "@/components/*": ["src/ui/*"], /* more specific */
+                    },
+                },
+            }"#,
+        )
+        .unwrap();
+        (dir, project)
+    }
+
+    fn resolver(project: &Project) -> ImportResolver {
+        ImportResolver::load(project, &project.path("client/src/components")).unwrap()
+    }
+
+    #[test]
+    fn resolves_aliases_with_the_longest_prefix_first() {
+        let (_dir, project) = project();
+        let resolver = resolver(&project);
+        let pages = project.path("pages");
+        assert_eq!(
+            resolver.resolve("@/components/Card", &pages),
+            Some(project.path("client/src/ui/Card.tsx"))
+        );
+        assert_eq!(
+            resolver.resolve("@/lib/format", &pages),
+            Some(project.path("client/src/lib/format.ts"))
+        );
+        assert_eq!(resolver.resolve("@/lib/missing", &pages), None);
+    }
+
+    #[test]
+    fn treats_aliases_as_local_and_packages_as_not() {
+        let (_dir, project) = project();
+        let resolver = resolver(&project);
+        assert!(resolver.is_local("@/lib/format"));
+        assert!(resolver.is_local("./Card"));
+        assert!(!resolver.is_local("react"));
+        assert!(!resolver.is_local("@types/react"));
+    }
+
+    #[test]
+    fn suggests_aliased_specifiers() {
+        let (_dir, project) = project();
+        let resolver = resolver(&project);
+        let pages = project.path("pages");
+        assert_eq!(
+            resolver.specifier_for(&project.path("client/src/ui/Card.tsx"), &pages),
+            "@/components/Card"
+        );
+        assert_eq!(
+            resolver.specifier_for(&project.path("client/src/lib/format.ts"), &pages),
+            "@/lib/format"
+        );
+    }
+
+    #[test]
+    fn resolves_mappings_from_the_parent_of_the_component_directory() {
+        let (_dir, project) = project();
+        let resolver = resolver(&project);
+        let mapping = ComponentMapping::parse("h2=components/Card").unwrap();
+        assert_eq!(
+            PathBuf::from(resolver.resolve_mapping(&mapping).unwrap().path),
+            project.path("client/src/components/Card.tsx")
+        );
+        let mapping = ComponentMapping::parse("h2=components/Missing").unwrap();
+        assert!(resolver.resolve_mapping(&mapping).is_err());
+    }
+
+    #[test]
+    fn works_without_a_tsconfig() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = Project::new(dir.path());
+        let resolver = resolver(&project);
+        assert!(!resolver.is_local("@/lib/format"));
+    }
+
+    #[test]
+    fn strips_comments_and_trailing_commas_outside_strings() {
+        assert_eq!(
+            strip_jsonc(r#"{ "a": "// not a comment", /* gone */ "b": [1, 2,], }"#),
+            r#"{ "a": "// not a comment",  "b": [1, 2]}"#
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cache::hash_bytes;
+use crate::css::scope_classes;
+use crate::error::{Error, Result};
+use crate::imports::specifier_between;
+use crate::project::Project;
+use crate::resolve::ImportResolver;
+use crate::validate::COMPONENT_EXTENSIONS;
+
+/// Extensions of the Sass stylesheets compiled before bundling, in SCSS and the indented
+/// syntax.
+pub const SASS_EXTENSIONS: &[&str] = &["scss", "sass"];
+
+pub fn is_sass(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| SASS_EXTENSIONS.contains(&ext))
+}
+
+/// Whether `path` is a CSS module like `Button.module.css` or `Button.module.scss`, whose
+/// class names are scoped to it.
+fn is_css_module(path: &Path) -> bool {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .is_some_and(|stem| stem.ends_with(".module"))
+}
+
+/// Where compiled stylesheets and the modules rewritten to import them are written.
+pub fn sass_dir(project: &Project) -> PathBuf {
+    project.cache_dir().join("sass")
+}
+
+/// The Sass stylesheets and CSS modules of a build compiled to CSS. esbuild can't load
+/// Sass or scope class names, so each module that imports one, directly or through other
+/// local modules, is copied with its imports pointing at the compiled output, and the copy
+/// is bundled instead.
+#[derive(Debug, Default)]
+pub struct CompiledSass {
+    /// The CSS compiled from each Sass file and CSS module.
+    stylesheets: BTreeMap<PathBuf, PathBuf>,
+    /// What to bundle in place of each file that reaches a Sass file: a rewritten copy of
+    /// a module, the CSS compiled from a stylesheet, or the class names of a CSS module.
+    replacements: BTreeMap<PathBuf, PathBuf>,
+    /// Why each Sass file that failed to compile did.
+    errors: BTreeMap<PathBuf, String>,
+}
+
+impl CompiledSass {
+    /// Compiles every Sass file and CSS module reachable from `modules` into [`sass_dir`]. `@use` and
+    /// `@import` in Sass look next to the importing file, then in the component
+    /// directory's `styles` and in `client/node_modules`.
+    pub fn compile(
+        project: &Project,
+        component_dir: &Path,
+        modules: &[PathBuf],
+        resolver: &ImportResolver,
+    ) -> Result<Self> {
+        let dir = sass_dir(project);
+        if dir.exists() {
+            fs::remove_dir_all(&dir).map_err(|e| Error::io(&dir, e))?;
+        }
+        fs::create_dir_all(&dir).map_err(|e| Error::io(&dir, e))?;
+
+        let mut compiler = Compiler {
+            resolver,
+            dir: dir.canonicalize().map_err(|e| Error::io(&dir, e))?,
+            load_paths: vec![
+                component_dir.join("styles"),
+                project.path("client/node_modules"),
+            ],
+            visited: BTreeSet::new(),
+            compiled: CompiledSass::default(),
+        };
+        for module in modules {
+            compiler.visit(module)?;
+        }
+        Ok(compiler.compiled)
+    }
+
+    /// The file to bundle in place of `module`.
+    pub fn replacement<'a>(&'a self, module: &'a Path) -> &'a Path {
+        self.replacements
+            .get(module)
+            .map_or(module, PathBuf::as_path)
+    }
+
+    /// The CSS to bundle in place of `sheet`.
+    pub fn stylesheet<'a>(&'a self, sheet: &'a Path) -> &'a Path {
+        self.stylesheets.get(sheet).map_or(sheet, PathBuf::as_path)
+    }
+
+    /// Why `sheet` failed to compile, if it did.
+    pub fn error(&self, sheet: &Path) -> Option<&str> {
+        self.errors.get(sheet).map(String::as_str)
+    }
+}
+
+struct Compiler<'a> {
+    resolver: &'a ImportResolver,
+    dir: PathBuf,
+    load_paths: Vec<PathBuf>,
+    /// Files visited so far, including the ones still being visited. An import cycle
+    /// leads back to the original module rather than its copy.
+    visited: BTreeSet<PathBuf>,
+    compiled: CompiledSass,
+}
+
+impl Compiler<'_> {
+    /// Works out what to bundle in place of `file`, if it reaches a Sass file or a CSS
+    /// module.
+    fn visit(&mut self, file: &Path) -> Result<Option<PathBuf>> {
+        if !self.visited.insert(file.to_path_buf()) {
+            return Ok(self.compiled.replacements.get(file).cloned());
+        }
+
+        let is_module = file
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| COMPONENT_EXTENSIONS.contains(&ext));
+        let replacement = if is_sass(file) {
+            self.compile_sheet(file)?
+        } else if is_css_module(file) {
+            match fs::read_to_string(file) {
+                Ok(css) => Some(self.write_sheet(file, css)?),
+                Err(_) => None,
+            }
+        } else if is_module {
+            self.rewrite_module(file)?
+        } else {
+            None
+        };
+
+        if let Some(replacement) = &replacement {
+            self.compiled
+                .replacements
+                .insert(file.to_path_buf(), replacement.clone());
+        }
+        Ok(replacement)
+    }
+
+    /// A directory of its own for the output of `file`, named after its path.
+    fn output_dir(&self, file: &Path) -> Result<PathBuf> {
+        let dir = self
+            .dir
+            .join(&hash_bytes(file.to_string_lossy().as_bytes())[..12]);
+        fs::create_dir_all(&dir).map_err(|e| Error::io(&dir, e))?;
+        Ok(dir)
+    }
+
+    fn compile_sheet(&mut self, file: &Path) -> Result<Option<PathBuf>> {
+        let options = grass::Options::default().load_paths(&self.load_paths);
+        let css = match grass::from_path(file, &options) {
+            Ok(css) => css,
+            Err(e) => {
+                self.compiled
+                    .errors
+                    .insert(file.to_path_buf(), e.to_string());
+                return Ok(None);
+            }
+        };
+        self.write_sheet(file, css).map(Some)
+    }
+
+    /// Writes the CSS for the stylesheet `file`, returning what to bundle in place of it:
+    /// the CSS itself, or for a CSS module a module exporting its class names.
+    fn write_sheet(&mut self, file: &Path, css: String) -> Result<PathBuf> {
+        let dir = self.output_dir(file)?;
+        let name = file
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default();
+        let stylesheet = dir.join(Path::new(name).with_extension("css"));
+        self.compiled
+            .stylesheets
+            .insert(file.to_path_buf(), stylesheet.clone());
+        if !is_css_module(file) {
+            fs::write(&stylesheet, css).map_err(|e| Error::io(&stylesheet, e))?;
+            return Ok(stylesheet);
+        }
+
+        // Classes are named after the file, with a hash of its path so two files of the
+        // same name don't clash
+        let prefix = name.split('.').next().unwrap_or_default();
+        let scope = &hash_bytes(file.to_string_lossy().as_bytes())[..5];
+        let rename = |class: &str| {
+            let class: String = class
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            format!("{}_{}_{}", prefix, class, scope)
+        };
+        let (css, classes) = scope_classes(&css, &rename);
+        fs::write(&stylesheet, css).map_err(|e| Error::io(&stylesheet, e))?;
+
+        // The class names module imports the stylesheet, so it's still tracked as a
+        // dependency of the pages using it. Names are exported camelCased only, as
+        // `localsConvention: "camelCaseOnly"` did when CSS modules went through esbuild
+        let class_names = dir.join(format!("{}.js", name));
+        let classes: BTreeMap<_, _> = classes
+            .into_iter()
+            .map(|(class, scoped)| (camel_case(&class), scoped))
+            .collect();
+        let classes = serde_json::to_string_pretty(&classes)
+            .map_err(|e| Error::io(&class_names, e.into()))?;
+        let source = format!(
+            "import \"{}\";\n\nexport default {};\n",
+            specifier_between(&dir, &stylesheet),
+            classes
+        );
+        fs::write(&class_names, source).map_err(|e| Error::io(&class_names, e))?;
+        Ok(class_names)
+    }
+
+    /// Copies `file` with its local imports rewritten if any of them reaches a Sass file.
+    fn rewrite_module(&mut self, file: &Path) -> Result<Option<PathBuf>> {
+        let Ok(contents) = fs::read_to_string(file) else {
+            return Ok(None);
+        };
+        let mut imports = Vec::new();
+        for (specifier, target) in self.resolver.local_imports(file, &contents) {
+            let replacement = self.visit(&target)?;
+            imports.push((specifier, target, replacement));
+        }
+        if imports
+            .iter()
+            .all(|(_, _, replacement)| replacement.is_none())
+        {
+            return Ok(None);
+        }
+
+        // The copy lives elsewhere, so every local import has to be rewritten, not just
+        // the ones that were replaced
+        let dir = self.output_dir(file)?;
+        let mut rewritten = contents.clone();
+        for (specifier, target, replacement) in &imports {
+            let target = replacement.as_ref().unwrap_or(target);
+            let new_specifier = specifier_between(&dir, target);
+            for quote in ['"', '\''] {
+                rewritten = rewritten.replace(
+                    &format!("{}{}{}", quote, specifier, quote),
+                    &format!("{}{}{}", quote, new_specifier, quote),
+                );
+            }
+        }
+
+        let copy = dir.join(file.file_name().unwrap_or_default());
+        fs::write(&copy, rewritten).map_err(|e| Error::io(&copy, e))?;
+        Ok(Some(copy))
+    }
+}
+
+/// `class` as a camelCased JavaScript name: `nav-item` and `nav_item` become `navItem`,
+/// and so does `NavItem`.
+fn camel_case(class: &str) -> String {
+    let mut words: Vec<String> = Vec::new();
+    let mut previous = None;
+    for c in class.chars() {
+        if !c.is_alphanumeric() {
+            previous = None;
+            continue;
+        }
+        let starts_word = match previous {
+            None => true,
+            Some(previous) => c.is_uppercase() && !char::is_uppercase(previous),
+        };
+        if starts_word {
+            words.push(String::new());
+        }
+        if let Some(word) = words.last_mut() {
+            word.extend(c.to_lowercase());
+        }
+        previous = Some(c);
+    }
+
+    words
+        .iter()
+        .enumerate()
+        .map(|(i, word)| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) if i > 0 => first.to_uppercase().chain(chars).collect(),
+                _ => word.clone(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn camel_cases_class_names() {
+        assert_eq!(camel_case("byline"), "byline");
+        assert_eq!(camel_case("nav-item"), "navItem");
+        assert_eq!(camel_case("nav_item--active"), "navItemActive");
+        assert_eq!(camel_case("NavItem"), "navItem");
+        assert_eq!(camel_case("navItem"), "navItem");
+        assert_eq!(camel_case("step-2"), "step2");
+    }
+
+    #[test]
+    fn recognises_css_modules() {
+        assert!(is_css_module(Path::new("Button.module.css")));
+        assert!(is_css_module(Path::new("Button.module.scss")));
+        assert!(!is_css_module(Path::new("Button.css")));
+        assert!(!is_css_module(Path::new("module.css")));
+    }
+}
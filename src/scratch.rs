@@ -0,0 +1,49 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, Result};
+use crate::project::Project;
+
+/// Directory under the build cache that holds every page's scratch directory.
+pub fn scratch_root(project: &Project) -> PathBuf {
+    project.cache_dir().join("pages")
+}
+
+/// A private directory holding one page's generated entries and bundles, so pages never
+/// share intermediate files. It's removed when dropped unless it was created with `keep`.
+#[derive(Debug)]
+pub struct ScratchDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl ScratchDir {
+    /// Creates the scratch directory for `page`, a path relative to the input directory,
+    /// at the same path under [`scratch_root`]. The location is stable between builds so
+    /// bundles mirrored from it get stable names; anything left there by an earlier build
+    /// is cleared first.
+    pub fn create(project: &Project, page: &Path, keep: bool) -> Result<Self> {
+        let path = scratch_root(project).join(page);
+        if path.exists() {
+            fs::remove_dir_all(&path).map_err(|e| Error::io(&path, e))?;
+        }
+        fs::create_dir_all(&path).map_err(|e| Error::io(&path, e))?;
+        Ok(Self { path, keep })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
@@ -0,0 +1,606 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::ops::AddAssign;
+use std::path::{Component, Path, PathBuf};
+
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use walkdir::WalkDir;
+
+use crate::error::{Error, Result};
+use crate::project::Project;
+use crate::scratch::scratch_root;
+
+/// File in the project's build directory the size of every page's client files is
+/// written to.
+pub const SIZE_REPORT_FILE: &str = "bundle-sizes.json";
+
+/// Directory in the project's cache that production sourcemaps are moved to, laid out
+/// like the static directory, so they're kept for measuring without being served.
+pub const SOURCEMAP_DIR: &str = "sourcemaps";
+
+/// What the bytes of a bundle that no sourcemap accounts for are attributed to, like
+/// esbuild's runtime helpers and the whitespace between modules.
+const UNATTRIBUTED: &str = "(unattributed)";
+
+/// How many of the largest modules an over budget page is blamed on.
+const BLAMED_MODULES: usize = 5;
+
+/// The `[budgets]` table of `kaffe.toml`. A page whose JS or CSS is larger than its
+/// budget fails the build.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BudgetConfig {
+    /// Which size budgets are compared against.
+    pub compression: Compression,
+    /// The most JS a page may load, counting every chunk it imports.
+    pub js: Option<ByteSize>,
+    /// The most CSS a page may load, counting the shared stylesheet.
+    pub css: Option<ByteSize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Raw,
+    #[default]
+    Gzip,
+    Brotli,
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::Raw => write!(f, "raw"),
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Brotli => write!(f, "brotli"),
+        }
+    }
+}
+
+/// A number of bytes, written in `kaffe.toml` as an integer or a string with a unit like
+/// `"150 kB"` or `"1.5 MiB"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        let split = text
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(text.len());
+        let (number, unit) = text.split_at(split);
+        let number: f64 = number.parse().ok()?;
+        let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1.0,
+            "kb" => 1e3,
+            "mb" => 1e6,
+            "kib" => 1024.0,
+            "mib" => 1024.0 * 1024.0,
+            _ => return None,
+        };
+        Some(Self((number * multiplier).round() as u64))
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Written {
+            Bytes(u64),
+            Text(String),
+        }
+
+        match Written::deserialize(deserializer)? {
+            Written::Bytes(bytes) => Ok(Self(bytes)),
+            Written::Text(text) => Self::parse(&text).ok_or_else(|| {
+                serde::de::Error::custom(format!(
+                    "invalid size `{}`, expected a number of bytes like \"150 kB\"",
+                    text
+                ))
+            }),
+        }
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            bytes if bytes < 1000 => write!(f, "{} B", bytes),
+            bytes if bytes < 1_000_000 => write!(f, "{:.1} kB", bytes as f64 / 1e3),
+            bytes => write!(f, "{:.2} MB", bytes as f64 / 1e6),
+        }
+    }
+}
+
+/// How large a file is as written, and compressed the way a server would send it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Sizes {
+    pub raw: u64,
+    pub gzip: u64,
+    pub brotli: u64,
+}
+
+impl Sizes {
+    pub fn get(&self, compression: Compression) -> u64 {
+        match compression {
+            Compression::Raw => self.raw,
+            Compression::Gzip => self.gzip,
+            Compression::Brotli => self.brotli,
+        }
+    }
+}
+
+impl AddAssign for Sizes {
+    fn add_assign(&mut self, other: Self) {
+        self.raw += other.raw;
+        self.gzip += other.gzip;
+        self.brotli += other.brotli;
+    }
+}
+
+impl fmt::Display for Sizes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} / {} / {}",
+            ByteSize(self.raw),
+            ByteSize(self.gzip),
+            ByteSize(self.brotli)
+        )
+    }
+}
+
+/// A file a page loads, with where esbuild wrote its sourcemap.
+#[derive(Debug, Clone)]
+pub struct PageFile {
+    pub path: PathBuf,
+    pub sourcemap: PathBuf,
+}
+
+/// The JS or the CSS one page loads.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AssetSizes {
+    /// Every file added up, which is what loading the page downloads when nothing is
+    /// cached.
+    pub total: Sizes,
+    /// Each file, relative to the static directory.
+    pub files: BTreeMap<String, Sizes>,
+    /// Bytes of every module before compression, largest first. Packages count as one
+    /// module, and local files are named relative to the project root.
+    pub modules: Vec<ModuleSize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModuleSize {
+    pub module: String,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PageSizes {
+    pub js: AssetSizes,
+    pub css: AssetSizes,
+}
+
+/// The size of every page's client files, keyed by the page's path relative to the input
+/// directory.
+#[derive(Debug, Default, Serialize)]
+pub struct SizeReport {
+    pub pages: BTreeMap<String, PageSizes>,
+    /// Files already measured, since pages share chunks and stylesheets.
+    #[serde(skip)]
+    measured: BTreeMap<PathBuf, (Sizes, BTreeMap<String, u64>)>,
+}
+
+impl SizeReport {
+    /// Measures the files `page` loads. Files without a sourcemap are attributed to
+    /// [`UNATTRIBUTED`] as a whole.
+    pub fn add_page(
+        &mut self,
+        project: &Project,
+        static_dir: &Path,
+        page: String,
+        js: &[PageFile],
+        css: &[PageFile],
+    ) -> Result<()> {
+        let sizes = PageSizes {
+            js: self.measure_all(project, static_dir, js)?,
+            css: self.measure_all(project, static_dir, css)?,
+        };
+        self.pages.insert(page, sizes);
+        Ok(())
+    }
+
+    fn measure_all(
+        &mut self,
+        project: &Project,
+        static_dir: &Path,
+        files: &[PageFile],
+    ) -> Result<AssetSizes> {
+        let mut assets = AssetSizes::default();
+        let mut modules = BTreeMap::new();
+        for file in files {
+            if !self.measured.contains_key(&file.path) {
+                let measured = measure(project, file)?;
+                self.measured.insert(file.path.clone(), measured);
+            }
+            let (sizes, file_modules) = &self.measured[&file.path];
+            assets.total += *sizes;
+            let name = file
+                .path
+                .strip_prefix(static_dir)
+                .unwrap_or(&file.path)
+                .iter()
+                .map(|c| c.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            assets.files.insert(name, *sizes);
+            for (module, bytes) in file_modules {
+                *modules.entry(module.clone()).or_insert(0) += bytes;
+            }
+        }
+        assets.modules = modules
+            .into_iter()
+            .map(|(module, bytes)| ModuleSize { module, bytes })
+            .collect();
+        assets
+            .modules
+            .sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.module.cmp(&b.module)));
+        Ok(assets)
+    }
+
+    pub fn save(&self, project: &Project) -> Result<()> {
+        let dir = project.build_dir();
+        fs::create_dir_all(&dir).map_err(|e| Error::io(&dir, e))?;
+        let path = dir.join(SIZE_REPORT_FILE);
+        let contents =
+            serde_json::to_string_pretty(self).map_err(|e| Error::io(&path, e.into()))?;
+        fs::write(&path, contents).map_err(|e| Error::io(&path, e))
+    }
+
+    /// Why each page over its budget is, keyed by the page.
+    pub fn over_budget(&self, budgets: &BudgetConfig) -> Vec<(&str, String)> {
+        let mut over = Vec::new();
+        for (page, sizes) in &self.pages {
+            let checks = [
+                ("JS", &sizes.js, budgets.js),
+                ("CSS", &sizes.css, budgets.css),
+            ];
+            for (kind, assets, budget) in checks {
+                let Some(budget) = budget else {
+                    continue;
+                };
+                let size = assets.total.get(budgets.compression);
+                if size <= budget.0 {
+                    continue;
+                }
+                let blamed: Vec<_> = assets
+                    .modules
+                    .iter()
+                    .take(BLAMED_MODULES)
+                    .map(|module| format!("{} ({})", module.module, ByteSize(module.bytes)))
+                    .collect();
+                over.push((
+                    page.as_str(),
+                    format!(
+                        "{} is {} {}, {} over the {} budget. Largest modules before \
+                         compression: {}",
+                        kind,
+                        ByteSize(size),
+                        budgets.compression,
+                        ByteSize(size - budget.0),
+                        budget,
+                        blamed.join(", ")
+                    ),
+                ));
+            }
+        }
+        over
+    }
+}
+
+/// A table of every page's JS and CSS, raw, gzipped and brotli compressed.
+impl fmt::Display for SizeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = self
+            .pages
+            .keys()
+            .map(String::len)
+            .chain(std::iter::once("Page".len()))
+            .max()
+            .unwrap_or_default();
+        let rows: Vec<_> = self
+            .pages
+            .iter()
+            .map(|(page, sizes)| {
+                (
+                    page,
+                    sizes.js.total.to_string(),
+                    sizes.css.total.to_string(),
+                )
+            })
+            .collect();
+        let js_width = rows
+            .iter()
+            .map(|(_, js, _)| js.len())
+            .chain(std::iter::once("JS (raw / gzip / brotli)".len()))
+            .max()
+            .unwrap_or_default();
+
+        write!(
+            f,
+            "{:width$}  {:js_width$}  CSS (raw / gzip / brotli)",
+            "Page", "JS (raw / gzip / brotli)"
+        )?;
+        for (page, js, css) in rows {
+            write!(f, "\n{:width$}  {:js_width$}  {}", page, js, css)?;
+        }
+        Ok(())
+    }
+}
+
+/// Moves every sourcemap in `static_dir` to the same path under `dest`. Their sources
+/// are made absolute, since they no longer sit where esbuild wrote them, and the page
+/// directories esbuild left behind for them are removed once empty.
+pub fn move_sourcemaps(static_dir: &Path, dest: &Path) -> Result<()> {
+    let maps: Vec<_> = WalkDir::new(static_dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && e.path().extension().is_some_and(|ext| ext == "map"))
+        .map(|e| e.into_path())
+        .collect();
+    for path in maps {
+        let contents = fs::read_to_string(&path).map_err(|e| Error::io(&path, e))?;
+        let mut map: serde_json::Value =
+            serde_json::from_str(&contents).map_err(|e| Error::io(&path, e.into()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        if let Some(sources) = map.get_mut("sources").and_then(|s| s.as_array_mut()) {
+            for source in sources {
+                if let Some(relative) = source.as_str() {
+                    *source = normalize(&dir.join(relative))
+                        .to_string_lossy()
+                        .into_owned()
+                        .into();
+                }
+            }
+        }
+
+        let target = dest.join(path.strip_prefix(static_dir).unwrap_or(&path));
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(|e| Error::io(parent, e))?;
+        }
+        fs::write(&target, map.to_string()).map_err(|e| Error::io(&target, e))?;
+        fs::remove_file(&path).map_err(|e| Error::io(&path, e))?;
+    }
+
+    for dir in WalkDir::new(static_dir)
+        .min_depth(1)
+        .contents_first(true)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_dir())
+    {
+        let _ = fs::remove_dir(dir.path());
+    }
+    Ok(())
+}
+
+fn measure(project: &Project, file: &PageFile) -> Result<(Sizes, BTreeMap<String, u64>)> {
+    let contents = fs::read(&file.path).map_err(|e| Error::io(&file.path, e))?;
+    let sizes = Sizes {
+        raw: contents.len() as u64,
+        gzip: gzip(&contents).map_err(|e| Error::io(&file.path, e))?,
+        brotli: brotli(&contents).map_err(|e| Error::io(&file.path, e))?,
+    };
+
+    let code = String::from_utf8_lossy(&contents);
+    let modules = fs::read_to_string(&file.sourcemap)
+        .ok()
+        .and_then(|map| serde_json::from_str::<Sourcemap>(&map).ok())
+        .map(|map| {
+            let dir = file.sourcemap.parent().unwrap_or(Path::new(""));
+            let names: Vec<_> = map
+                .sources
+                .iter()
+                .map(|source| module_name(project, &dir.join(source)))
+                .collect();
+            attribute(&code, &map.mappings, &names)
+        })
+        .unwrap_or_else(|| BTreeMap::from([(UNATTRIBUTED.to_string(), sizes.raw)]));
+    Ok((sizes, modules))
+}
+
+fn gzip(contents: &[u8]) -> std::io::Result<u64> {
+    let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(contents)?;
+    Ok(encoder.finish()?.len() as u64)
+}
+
+fn brotli(contents: &[u8]) -> std::io::Result<u64> {
+    let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+    encoder.write_all(contents)?;
+    encoder.flush()?;
+    Ok(encoder.into_inner().len() as u64)
+}
+
+#[derive(Deserialize)]
+struct Sourcemap {
+    sources: Vec<String>,
+    mappings: String,
+}
+
+/// Adds up the bytes of `code` each source in a sourcemap's `mappings` produced. A
+/// mapping covers its line up to the next one, and whatever no mapping covers is
+/// [`UNATTRIBUTED`].
+fn attribute(code: &str, mappings: &str, sources: &[String]) -> BTreeMap<String, u64> {
+    let mut modules = BTreeMap::new();
+    let mut add = |source: Option<&String>, bytes: usize| {
+        if bytes > 0 {
+            let name = source.map_or(UNATTRIBUTED, String::as_str);
+            *modules.entry(name.to_string()).or_insert(0) += bytes as u64;
+        }
+    };
+
+    let mut lines = mappings.split(';');
+    let mut source_index = 0i64;
+    for line in code.split_inclusive('\n') {
+        let text = line.strip_suffix('\n').unwrap_or(line);
+        add(None, line.len() - text.len());
+
+        // Columns count UTF-16 code units, and restart on every line
+        let mut boundaries = Vec::new();
+        let mut column = 0i64;
+        for segment in lines.next().unwrap_or_default().split(',') {
+            let fields = decode_vlq(segment);
+            let Some(&column_delta) = fields.first() else {
+                continue;
+            };
+            column += column_delta;
+            let source = match fields.get(1) {
+                Some(&delta) => {
+                    source_index += delta;
+                    usize::try_from(source_index)
+                        .ok()
+                        .and_then(|i| sources.get(i))
+                }
+                None => None,
+            };
+            boundaries.push((utf16_offset(text, column), source));
+        }
+        boundaries.sort_by_key(|(offset, _)| *offset);
+
+        let mut start = 0;
+        let mut current = None;
+        for (offset, source) in boundaries {
+            add(current, offset.saturating_sub(start));
+            start = start.max(offset);
+            current = source;
+        }
+        add(current, text.len().saturating_sub(start));
+    }
+    modules
+}
+
+/// The byte offset in `text` of a column counted in UTF-16 code units.
+fn utf16_offset(text: &str, column: i64) -> usize {
+    let mut units = 0;
+    for (offset, c) in text.char_indices() {
+        if units >= column {
+            return offset;
+        }
+        units += c.len_utf16() as i64;
+    }
+    text.len()
+}
+
+fn decode_vlq(segment: &str) -> Vec<i64> {
+    const DIGITS: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut values = Vec::new();
+    let (mut value, mut shift) = (0i64, 0);
+    for byte in segment.bytes() {
+        let Some(digit) = DIGITS.iter().position(|&d| d == byte) else {
+            break;
+        };
+        let digit = digit as i64;
+        value += (digit & 31) << shift;
+        if digit & 32 != 0 {
+            shift += 5;
+            continue;
+        }
+        values.push(if value & 1 == 1 {
+            -(value >> 1)
+        } else {
+            value >> 1
+        });
+        value = 0;
+        shift = 0;
+    }
+    values
+}
+
+/// What a source in a sourcemap is reported as: the package for files in
+/// `node_modules`, otherwise the file relative to the page scratch directories or the
+/// project root.
+fn module_name(project: &Project, source: &Path) -> String {
+    let source = normalize(source);
+    let components: Vec<_> = source
+        .iter()
+        .map(|c| c.to_string_lossy().into_owned())
+        .collect();
+    if let Some(i) = components.iter().rposition(|c| c == "node_modules") {
+        let package = &components[i + 1..];
+        let length = if package.first().is_some_and(|c| c.starts_with('@')) {
+            2
+        } else {
+            1
+        };
+        return package[..length.min(package.len())].join("/");
+    }
+
+    let scratch = normalize(&scratch_root(project));
+    let root = normalize(&project.path(""));
+    let relative = source
+        .strip_prefix(&scratch)
+        .or_else(|_| source.strip_prefix(&root))
+        .unwrap_or(&source);
+    relative
+        .iter()
+        .map(|c| c.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// `path` with `.` and `..` resolved without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    normalized.push("..");
+                }
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn moves_sourcemaps_out_of_the_static_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let static_dir = dir.path().join("output/static");
+        let dest = dir.path().join("sourcemaps");
+        let page_dir = static_dir.join("blog/post.md");
+        fs::create_dir_all(&page_dir).unwrap();
+        fs::write(static_dir.join("post.3fa9c2d1.js"), "x").unwrap();
+        fs::write(
+            page_dir.join("client-entry.js.map"),
+            r#"{"version":3,"sources":["../../../../client/src/Counter.tsx"],"mappings":"A"}"#,
+        )
+        .unwrap();
+
+        move_sourcemaps(&static_dir, &dest).unwrap();
+
+        assert!(!page_dir.exists());
+        assert!(static_dir.join("post.3fa9c2d1.js").is_file());
+        let map: Sourcemap = serde_json::from_str(
+            &fs::read_to_string(dest.join("blog/post.md/client-entry.js.map")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            PathBuf::from(&map.sources[0]),
+            dir.path().join("client/src/Counter.tsx")
+        );
+        assert_eq!(map.mappings, "A");
+    }
+}
@@ -0,0 +1,250 @@
+use minijinja::{context, path_loader, Environment, ErrorKind, UndefinedBehavior, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::codegen::Heading;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::manifest::Manifest;
+use crate::mode::Mode;
+use crate::parser::Frontmatter;
+use crate::project::{Project, TemplateFile};
+use crate::public::PublicFiles;
+
+/// Directory, relative to the project root, that `{% include %}` and `{% extends %}`
+/// load partials from.
+pub const PARTIALS_DIR: &str = "client/templates";
+
+/// Variables every `template.html` has to use for the page to work.
+const REQUIRED_VARIABLES: &[&str] = &["ssr_content", "assets"];
+
+/// What templates see of every page in the site, available as `page` and `pages`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageSummary {
+    pub url: String,
+    pub title: String,
+    pub frontmatter: Frontmatter,
+    pub headings: Vec<Heading>,
+}
+
+/// The built assets a page references, available as `assets`. Paths are relative to
+/// `/static`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PageAssets {
+    pub js: String,
+    /// Stylesheets to link, in order. Empty when the page imports no CSS.
+    pub css: Vec<String>,
+    /// Shared chunks `js` imports, for `<link rel="modulepreload">` tags.
+    pub preload: Vec<String>,
+    /// The rules of `css` the top of the page needs, to inline so the stylesheets can
+    /// load without blocking rendering. Empty unless `--critical-css` was passed.
+    pub critical_css: String,
+}
+
+/// What every page's template shares, available as `site`, `pages`, `mode`, `asset()`
+/// and `public()`. Built once per build, since the functions own their lookup tables.
+pub struct SiteContext<'a> {
+    pub config: &'a Config,
+    pub pages: &'a [PageSummary],
+    pub mode: Mode,
+    asset: Value,
+    public: Value,
+}
+
+impl<'a> SiteContext<'a> {
+    pub fn new(
+        config: &'a Config,
+        pages: &'a [PageSummary],
+        manifest: &Manifest,
+        public: &PublicFiles,
+        mode: Mode,
+    ) -> Self {
+        let published = manifest.assets().clone();
+        let asset = move |name: &str| {
+            published.get(name).cloned().ok_or_else(|| {
+                minijinja::Error::new(
+                    ErrorKind::InvalidOperation,
+                    format!("no asset named `{}` in the manifest", name),
+                )
+            })
+        };
+
+        let public_files = public.clone();
+        let public = move |url: &str| {
+            public_files.get(url).ok_or_else(|| {
+                minijinja::Error::new(
+                    ErrorKind::InvalidOperation,
+                    format!("no file at `{}` in public/", url),
+                )
+            })
+        };
+
+        Self {
+            config,
+            pages,
+            mode,
+            asset: Value::from_function(asset),
+            public: Value::from_function(public),
+        }
+    }
+}
+
+/// Renders `template.html` with Jinja syntax, HTML auto-escaping and errors on undefined
+/// variables.
+pub struct TemplateRenderer {
+    env: Environment<'static>,
+}
+
+impl TemplateRenderer {
+    pub fn new(project: &Project) -> Self {
+        let mut env = Environment::new();
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        env.set_loader(path_loader(project.path(PARTIALS_DIR)));
+        Self { env }
+    }
+
+    pub fn render(
+        &self,
+        template: &TemplateFile,
+        page: &PageSummary,
+        ssr_content: &str,
+        assets: &PageAssets,
+        site: &SiteContext,
+    ) -> Result<String> {
+        let template_error = |e: minijinja::Error| Error::Template {
+            file: template.path.clone(),
+            message: format!("{:#}", e),
+        };
+
+        // The file name decides auto-escaping, so the template is always named as HTML
+        let mut name = template.path.display().to_string();
+        if !name.ends_with(".html") {
+            name.push_str(".html");
+        }
+        let compiled = self
+            .env
+            .template_from_named_str(&name, &template.contents)
+            .map_err(template_error)?;
+
+        // A layout the template extends or a partial it includes can use the variables
+        // instead, and only the template's own source is visible here, so those templates
+        // are checked by what they render
+        let partials = uses_partials(&template.contents);
+        if !partials {
+            let used = compiled.undeclared_variables(false);
+            let missing: Vec<_> = REQUIRED_VARIABLES
+                .iter()
+                .filter(|variable| !used.contains(**variable))
+                .copied()
+                .collect();
+            if !missing.is_empty() {
+                return Err(Error::Template {
+                    file: template.path.clone(),
+                    message: format!("missing required variable(s) {}", missing.join(", ")),
+                });
+            }
+        }
+
+        let html = compiled
+            .render(context! {
+                title => &page.title,
+                ssr_content => Value::from_safe_string(ssr_content.to_string()),
+                page => page,
+                pages => site.pages,
+                site => &site.config.site,
+                assets => assets,
+                asset => &site.asset,
+                public => &site.public,
+                mode => site.mode,
+            })
+            .map_err(template_error)?;
+        if partials && !html.contains(ssr_content) {
+            return Err(Error::Template {
+                file: template.path.clone(),
+                message: "the page's content is missing from the output, render `ssr_content` \
+                          in the template or a layout it extends"
+                    .to_string(),
+            });
+        }
+        Ok(html)
+    }
+}
+
+/// Whether `source` has an `{% extends %}` or `{% include %}` tag.
+fn uses_partials(source: &str) -> bool {
+    source.split("{%").skip(1).any(|tag| {
+        let tag = tag.trim_start_matches(['-', '+']).trim_start();
+        tag.starts_with("extends") || tag.starts_with("include")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn page() -> PageSummary {
+        PageSummary {
+            url: "/".to_string(),
+            title: "Home".to_string(),
+            frontmatter: Frontmatter::new(),
+            headings: Vec::new(),
+        }
+    }
+
+    fn render(project: &Project, contents: &str) -> Result<String> {
+        let config = Config::default();
+        let site = SiteContext::new(
+            &config,
+            &[],
+            &Manifest::default(),
+            &PublicFiles::default(),
+            Mode::Dev,
+        );
+        let template = TemplateFile {
+            path: PathBuf::from("template.html"),
+            contents: contents.to_string(),
+        };
+        let assets = PageAssets {
+            js: "index.js".to_string(),
+            ..Default::default()
+        };
+        TemplateRenderer::new(project).render(&template, &page(), "<p>Hi</p>", &assets, &site)
+    }
+
+    #[test]
+    fn requires_content_and_assets() {
+        let project = Project::new("/nonexistent");
+        let html = render(&project, "{{ ssr_content }}{{ assets.js }}").unwrap();
+        assert_eq!(html, "<p>Hi</p>index.js");
+        assert!(render(&project, "{{ ssr_content }}").is_err());
+    }
+
+    #[test]
+    fn checks_templates_extending_a_layout_by_their_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let project = Project::new(dir.path());
+        let partials = project.path(PARTIALS_DIR);
+        fs::create_dir_all(&partials).unwrap();
+        fs::write(
+            partials.join("layout.html"),
+            "<title>{{ title }}</title>{% block body %}{% endblock %}\
+             <script src=\"/static/{{ assets.js }}\"></script>",
+        )
+        .unwrap();
+
+        let html = render(
+            &project,
+            "{% extends \"layout.html\" %}{% block body %}{{ ssr_content }}{% endblock %}",
+        )
+        .unwrap();
+        assert_eq!(
+            html,
+            "<title>Home</title><p>Hi</p><script src=\"/static/index.js\"></script>"
+        );
+
+        let empty = render(&project, "{%- extends \"layout.html\" %}");
+        assert!(empty.is_err());
+    }
+}
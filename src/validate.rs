@@ -0,0 +1,271 @@
+use std::path::{Path, PathBuf};
+
+use walkdir::WalkDir;
+
+use crate::diagnostic::{Diagnostic, Span};
+use crate::loader::LOADER_EXPORT;
+use crate::parser::{ASTNode, Document, Rendering, DIRECTIVE_PREFIX};
+use crate::resolve::ImportResolver;
+
+pub const COMPONENT_EXTENSIONS: &[&str] = &["tsx", "jsx", "ts", "js"];
+
+/// The names (file stems, without a `.server` or `.client` suffix) and paths of the
+/// component files available to pages.
+#[derive(Debug, Default)]
+pub struct ComponentIndex {
+    components: Vec<(String, PathBuf)>,
+}
+
+impl ComponentIndex {
+    pub fn scan(component_dir: &Path) -> Self {
+        let components = WalkDir::new(component_dir)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter(|e| {
+                e.path()
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| COMPONENT_EXTENSIONS.contains(&ext))
+            })
+            .filter_map(|e| {
+                let (name, _) = Rendering::of_name(e.path().file_stem()?.to_str()?);
+                let name = name.to_string();
+                Some((name, e.into_path()))
+            })
+            .collect();
+
+        Self { components }
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.path(name).is_some()
+    }
+
+    pub fn path(&self, name: &str) -> Option<&Path> {
+        self.components
+            .iter()
+            .find(|(stem, _)| stem == name)
+            .map(|(_, path)| path.as_path())
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.components.iter().map(|(stem, _)| stem.as_str())
+    }
+}
+
+/// Cross-checks the components the page at `page` renders against what it imports and
+/// what exists in the component directory. Whether the imports exist is checked by
+/// [`crate::resolve::resolve_imports`].
+pub fn validate_components(
+    document: &Document,
+    page: &Path,
+    index: &ComponentIndex,
+    resolver: &ImportResolver,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut imported: Vec<(String, Span)> = Vec::new();
+    let mut used: Vec<(&str, Span)> = Vec::new();
+
+    for (node, span) in document.iter() {
+        match node {
+            ASTNode::Import(import) => {
+                for binding in import.bindings() {
+                    imported.push((binding, *span));
+                }
+            }
+            ASTNode::ReactComponent(name, props, _) => {
+                used.push((name, *span));
+                for prop in props {
+                    if prop.name.starts_with(DIRECTIVE_PREFIX) {
+                        diagnostics.push(invalid_directive(&prop.name).with_span(*span));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (name, span) in &used {
+        if imported.iter().any(|(binding, _)| binding == name) {
+            continue;
+        }
+
+        let mut diagnostic =
+            Diagnostic::error(format!("Component `{}` is used but never imported", name))
+                .with_span(*span);
+        if let Some(file) = index.path(name) {
+            let dir = page.parent().unwrap_or(Path::new(""));
+            diagnostic = diagnostic.with_help(format!(
+                "add `import {} from \"{}\";`",
+                name,
+                resolver.specifier_for(file, dir)
+            ));
+        } else {
+            let candidates = imported
+                .iter()
+                .map(|(binding, _)| binding.as_str())
+                .chain(index.names());
+            if let Some(suggestion) = closest_match(name, candidates) {
+                diagnostic = diagnostic.with_help(format!("did you mean `{}`?", suggestion));
+            }
+        }
+        diagnostics.push(diagnostic);
+    }
+
+    for (binding, span) in &imported {
+        if binding != LOADER_EXPORT && !used.iter().any(|(name, _)| name == binding) {
+            diagnostics.push(
+                Diagnostic::warning(format!("`{}` is imported but never used", binding))
+                    .with_span(*span),
+            );
+        }
+    }
+
+    diagnostics
+}
+
+/// A `client:` attribute that isn't one of the directives, or has the wrong value.
+fn invalid_directive(name: &str) -> Diagnostic {
+    const DIRECTIVES: &[&str] = &[
+        "client:load",
+        "client:idle",
+        "client:visible",
+        "client:media",
+        "client:none",
+        "client:only",
+    ];
+    if name == "client:media" {
+        return Diagnostic::error("`client:media` needs a media query")
+            .with_help("write it like `client:media=\"(max-width: 600px)\"`");
+    }
+    if name == "client:only" {
+        return Diagnostic::error("`client:only` takes its fallback text as a string")
+            .with_help("write it like `client:only=\"Loading…\"`, or leave the value out");
+    }
+    if DIRECTIVES.contains(&name) {
+        return Diagnostic::error(format!("`{}` doesn't take a value", name));
+    }
+    let diagnostic = Diagnostic::error(format!("Unknown directive `{}`", name));
+    match closest_match(name, DIRECTIVES.iter().copied()) {
+        Some(suggestion) => diagnostic.with_help(format!("did you mean `{}`?", suggestion)),
+        None => diagnostic.with_help(format!("expected one of {}", DIRECTIVES.join(", "))),
+    }
+}
+
+pub fn closest_match<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let threshold = (name.len() / 3).max(1);
+    candidates
+        .filter(|candidate| *candidate != name)
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= threshold)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Case-insensitive Levenshtein distance.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_edit_distance_ignoring_case() {
+        assert_eq!(edit_distance("Counter", "counter"), 0);
+        assert_eq!(edit_distance("Countr", "Counter"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "Card"), 4);
+    }
+
+    #[test]
+    fn suggests_the_closest_candidate() {
+        let candidates = ["Card", "Counter", "Chart"];
+        assert_eq!(
+            closest_match("Countr", candidates.iter().copied()),
+            Some("Counter")
+        );
+        assert_eq!(
+            closest_match("Cart", candidates.iter().copied()),
+            Some("Card")
+        );
+    }
+
+    #[test]
+    fn suggests_nothing_too_different_or_identical() {
+        let candidates = ["Counter", "Navigation"];
+        assert_eq!(closest_match("Footer", candidates.iter().copied()), None);
+        assert_eq!(closest_match("Counter", candidates.iter().copied()), None);
+    }
+
+    fn diagnose(component: &str) -> Vec<Diagnostic> {
+        let source = format!(
+            "import Counter from \"./Counter\";\n\n{}\n\nText\n",
+            component
+        );
+        let document = crate::parser::parse_document(&source).unwrap();
+        validate_components(
+            &document,
+            Path::new("pages/index.md"),
+            &ComponentIndex::default(),
+            &ImportResolver::default(),
+        )
+    }
+
+    #[test]
+    fn accepts_valid_directives() {
+        assert!(diagnose("<Counter client:visible />").is_empty());
+        assert!(diagnose("<Counter client:only=\"Loading…\" />").is_empty());
+    }
+
+    #[test]
+    fn reports_directives_missing_their_value() {
+        let diagnostics = diagnose("<Counter client:media />");
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0].is_error());
+        assert_eq!(diagnostics[0].message, "`client:media` needs a media query");
+        assert!(diagnostics[0].span.is_some());
+    }
+
+    #[test]
+    fn reports_directives_with_the_wrong_value() {
+        let diagnostics = diagnose("<Counter client:only={true} />");
+        assert_eq!(
+            diagnostics[0].message,
+            "`client:only` takes its fallback text as a string"
+        );
+        let diagnostics = diagnose("<Counter client:idle=\"soon\" />");
+        assert_eq!(diagnostics[0].message, "`client:idle` doesn't take a value");
+    }
+
+    #[test]
+    fn suggests_directives_for_unknown_ones() {
+        let diagnostics = diagnose("<Counter client:visibel />");
+        assert_eq!(diagnostics[0].message, "Unknown directive `client:visibel`");
+        assert_eq!(
+            diagnostics[0].help.as_deref(),
+            Some("did you mean `client:visible`?")
+        );
+
+        let diagnostics = diagnose("<Counter client:whenever-you-like />");
+        assert!(diagnostics[0]
+            .help
+            .as_deref()
+            .is_some_and(|help| help.starts_with("expected one of client:load")));
+    }
+}